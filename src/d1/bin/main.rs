@@ -36,88 +36,865 @@
 
 extern crate aoc_2020;
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, HashMap};
 use aoc_2020::util;
 
 pub fn main() {
-    let mut nums = BTreeSet::new();
+    let mut nums = Multiset::new();
     let lines = util::read_lines("inputs/d1")
         .expect("failed to open file inputs/d1");
     for line in lines {
         if let Ok(act) = line {
             let num = act.parse::<i64>().expect("failed to parse int");
-            nums.insert(num);
+            *nums.entry(num).or_insert(0) += 1;
         }
     }
 
-    if let Some((x,y)) = find_sum_pair(&nums, 2020) {
-        println!("Found pair {}, {} that sum to 2020, product is: {}", x, y, x * y);
+    // each expense report entry may be used at most once
+    let mode = SumMode::DistinctIndices;
+    if let Some((x, y)) = find_sum_pair(&nums, 2020, mode) {
+        match checked_product(&[x, y]) {
+            Ok(p) => println!("Found pair {}, {} that sum to 2020, product is: {}", x, y, p),
+            Err(e) => println!("Found pair {}, {} that sum to 2020, but: {:?}", x, y, e),
+        }
     } else {
         println!("Could not find pair summing to 2020.");
     }
 
-    if let Some((x,y, z)) = find_sum_triple(&nums, 2020) {
-        println!(
-            "Found triple ({}, {}, {}) that sum to 2020, product is {}",
-            x, y, z, x * y * z
-        );
+    if let Some((x, y, z)) = find_sum_triple(&nums, 2020, mode) {
+        match checked_product(&[x, y, z]) {
+            Ok(p) => println!(
+                "Found triple ({}, {}, {}) that sum to 2020, product is {}",
+                x, y, z, p
+            ),
+            Err(e) => println!("Found triple ({}, {}, {}) that sum to 2020, but: {:?}", x, y, z, e),
+        }
     } else {
         println!("Could not find triple summing to 2020.");
     }
+
+    for k in 2..=3 {
+        println!("Number of {}-tuples summing to 2020: {}", k, count_k_sums(&nums, k, 2020, mode));
+        for tuple in k_sums(&nums, k, 2020, mode) {
+            println!("  {:?}, product {:?}", tuple, wide_product(&tuple));
+        }
+    }
+
+    match find_subset_sum(&nums, 2020) {
+        Ok(Some(subset)) => println!("Found subset {:?} that sums to 2020", subset),
+        Ok(None) => println!("Could not find subset summing to 2020."),
+        Err(e) => println!("Subset sum failed: {}", e),
+    }
+
+    if let Some(v) = find_k_sum_at_most(&nums, 3, 2019, mode) {
+        println!("Closest triple to 2019 without exceeding it: {:?}, sum {}", v, v.iter().sum::<i64>());
+    }
+}
+
+/// Expense report entries, mapping each value to the number of times it occurs.
+type Multiset = BTreeMap<i64, usize>;
+
+/// How entries of the expense report may be combined into a sum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SumMode {
+    /// Each entry is used at most once, but equal entries on different lines are distinct.
+    /// These are the puzzle's semantics.
+    DistinctIndices,
+    /// Any value in the report may be used any number of times.
+    RepeatValues,
+    /// Each value in the report is used at most once, however often it occurs.
+    DistinctValues,
+}
+
+impl SumMode {
+    /// Maximum number of times a value occurring `count` times may appear in a `k`-tuple.
+    fn cap(&self, count: usize, k: usize) -> usize {
+        match self {
+            SumMode::DistinctIndices => count,
+            SumMode::RepeatValues => k,
+            SumMode::DistinctValues => 1,
+        }
+    }
+}
+
+/// Error computing the product of expense report entries.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProductError {
+    /// The product of the given entries doesn't fit in the result type.
+    Overflow(Vec<i64>),
+}
+
+/// Multiply the entries of `tuple` together, failing instead of silently wrapping around if
+/// the product doesn't fit in an `i64`.
+fn checked_product(tuple: &[i64]) -> Result<i64, ProductError> {
+    tuple.iter()
+        .try_fold(1i64, |acc, x| acc.checked_mul(*x))
+        .ok_or_else(|| ProductError::Overflow(tuple.to_vec()))
+}
+
+/// Multiply the entries of `tuple` together as an `i128`. Products of up to two entries
+/// always fit; longer tuples are checked like in `checked_product`.
+fn wide_product(tuple: &[i64]) -> Result<i128, ProductError> {
+    tuple.iter()
+        .try_fold(1i128, |acc, x| acc.checked_mul(*x as i128))
+        .ok_or_else(|| ProductError::Overflow(tuple.to_vec()))
 }
 
-/// find a non-descreasing pair of integers in the set that adds to `sum`.
+/// find a non-descreasing pair of entries in the multiset that adds to `sum`, combining
+/// entries according to `mode`.
+fn find_sum_pair(nums: &Multiset, sum: i64, mode: SumMode) -> Option<(i64, i64)> {
+    find_k_sum(nums, 2, sum, mode).map(|v| (v[0], v[1]))
+}
+
+/// find a non-decreasing triple of entries in the multiset that adds to `sum`, combining
+/// entries according to `mode`.
+fn find_sum_triple(nums: &Multiset, sum: i64, mode: SumMode) -> Option<(i64, i64, i64)> {
+    find_k_sum(nums, 3, sum, mode).map(|v| (v[0], v[1], v[2]))
+}
+
+/// Smallest `k` for which `find_k_sum` switches from nested two-pointer scans to
+/// meet-in-the-middle.
+const MITM_MIN_K: usize = 4;
+
+/// Find a non-decreasing `k`-tuple of entries in the multiset that adds to `sum`, combining
+/// entries according to `mode`.
 ///
-/// Note: the integers in the returned pair may coincide
-fn find_sum_pair(nums: &BTreeSet<i64>, sum: i64) -> Option<(i64, i64)> {
-    for num in nums {
-        let comp = sum - num;
-        if nums.contains(&comp) {
-            // return in non-decreasing order for consistency
-            if *num <= comp {
-                return Some((*num, comp));
-            } else {
-                return Some((comp, *num));
+/// Small `k` reduce to a sorted two-pointer scan in O(n^(k-1)) time. Larger `k` use
+/// meet-in-the-middle: all sums of `k/2` entries are tabulated and the remaining
+/// `k - k/2` entries are looked up against them, in O(n^ceil(k/2)) time and space.
+fn find_k_sum(nums: &Multiset, k: usize, sum: i64, mode: SumMode) -> Option<Vec<i64>> {
+    let items = capped_items(nums, k, mode);
+    if k < MITM_MIN_K {
+        k_sum_two_pointer(&items, 0, k, sum as i128)
+    } else {
+        k_sum_mitm(&items, k, sum as i128)
+    }
+}
+
+/// Ascending `(value, cap)` pairs, where `cap` is the number of times `value` may be used in
+/// a `k`-tuple under `mode`. Values that may not be used at all are dropped.
+///
+/// The searches over these items take sums of entries as `i128` so that large entries can't
+/// overflow.
+fn capped_items(nums: &Multiset, k: usize, mode: SumMode) -> Vec<(i64, usize)> {
+    nums.iter()
+        .map(|(x, count)| (*x, mode.cap(*count, k)))
+        .filter(|(_, cap)| *cap > 0)
+        .collect()
+}
+
+/// Find a non-decreasing `k`-tuple from the ascending `(value, cap)` slice `items` summing to
+/// `sum` by fixing the smallest entry and recursing down to a two-pointer scan. `taken` copies
+/// of `items[0]` have already been used by the caller.
+fn k_sum_two_pointer(items: &[(i64, usize)], taken: usize, k: usize, sum: i128) -> Option<Vec<i64>> {
+    let avail = |i: usize| if i == 0 { items[0].1 - taken } else { items[i].1 };
+    match k {
+        0 => if sum == 0 { Some(Vec::new()) } else { None },
+        1 => items.binary_search_by_key(&sum, |(x, _)| *x as i128).ok()
+            .filter(|i| avail(*i) > 0)
+            .map(|i| vec![items[i].0]),
+        2 => {
+            if items.is_empty() {
+                return None;
+            }
+            let (mut lo, mut hi) = (0, items.len() - 1);
+            while lo <= hi {
+                let s = items[lo].0 as i128 + items[hi].0 as i128;
+                if s == sum && (lo < hi || avail(lo) >= 2) {
+                    return Some(vec![items[lo].0, items[hi].0]);
+                } else if s < sum {
+                    lo += 1;
+                } else if hi == 0 {
+                    break;
+                } else {
+                    hi -= 1;
+                }
             }
+            None
+        },
+        _ => {
+            for (i, (x, _)) in items.iter().enumerate() {
+                let left = avail(i);
+                if left == 0 {
+                    continue;
+                }
+                // remaining entries are drawn from items[i..] so the tuple stays non-decreasing
+                let rest = if left == 1 {
+                    k_sum_two_pointer(&items[i + 1..], 0, k - 1, sum - *x as i128)
+                } else {
+                    let used = if i == 0 { taken + 1 } else { 1 };
+                    k_sum_two_pointer(&items[i..], used, k - 1, sum - *x as i128)
+                };
+                if let Some(mut rest) = rest {
+                    rest.insert(0, *x);
+                    return Some(rest);
+                }
+            }
+            None
+        },
+    }
+}
+
+/// Find a non-decreasing `k`-tuple from the ascending `(value, cap)` slice `items` summing to
+/// `sum` by meeting in the middle.
+///
+/// The left half holds the `k/2` smallest entries of the tuple, so it only has to be checked
+/// against the right half at the value where they meet. For each sum, the left half kept is
+/// the one with the smallest largest entry, using it the fewest times, which is compatible
+/// with a right half whenever any left half with that sum is.
+fn k_sum_mitm(items: &[(i64, usize)], k: usize, sum: i128) -> Option<Vec<i64>> {
+    let left_k = k / 2;
+    let right_k = k - left_k;
+    let cap_of = |x: i64| items.binary_search_by_key(&x, |(y, _)| *y).map(|i| items[i].1).unwrap_or(0);
+
+    let wide_sum = |tuple: &[i64]| tuple.iter().map(|x| *x as i128).sum::<i128>();
+    let mut left_sums: HashMap<i128, ((i64, usize), Vec<i64>)> = HashMap::new();
+    for_each_multiset(items, left_k, &mut |tuple| {
+        let key = tuple.last().map(|x| (*x, run_length(tuple.iter().rev()))).unwrap_or((i64::MIN, 0));
+        let entry = left_sums.entry(wide_sum(tuple)).or_insert_with(|| (key, tuple.to_vec()));
+        if key < entry.0 {
+            *entry = (key, tuple.to_vec());
         }
+        true
+    });
+
+    let mut result = None;
+    for_each_multiset(items, right_k, &mut |tuple| {
+        if let Some(((left_max, left_count), left)) = left_sums.get(&(sum - wide_sum(tuple))) {
+            let compatible = match tuple.first() {
+                None => true,
+                Some(right_min) => *left_max < *right_min
+                    || (left_max == right_min
+                        && left_count + run_length(tuple.iter()) <= cap_of(*right_min)),
+            };
+            if compatible {
+                result = Some(left.iter().chain(tuple.iter()).cloned().collect());
+                return false;
+            }
+        }
+        true
+    });
+    result
+}
+
+/// Return a lazy iterator over every non-decreasing `k`-tuple of entries in the multiset that
+/// adds to `sum`, combining entries according to `mode`, in lexicographic order.
+///
+/// Entries with equal values are indistinguishable, so each tuple of values appears once.
+fn k_sums(nums: &Multiset, k: usize, sum: i64, mode: SumMode) -> KSums {
+    let items = capped_items(nums, k, mode);
+    let max = items.last().map(|(x, _)| *x).unwrap_or(0);
+    KSums {
+        items,
+        max,
+        k,
+        target: sum,
+        path: Vec::with_capacity(k),
+        path_sum: 0,
+        cursor: 0,
+        done: false,
     }
-    None
 }
 
-// find a non-decreasing triple that adds to `sum` by iterating once over the set of numbers.
-fn find_sum_triple(nums: &BTreeSet<i64>, sum: i64) -> Option<(i64, i64, i64)> {
-    for num in nums {
-        let sub_sum = sum - num;
-        if let Some((x,y)) = find_sum_pair(&nums, sub_sum) {
-            // allocate a tmp vector to sort the ints
-            let mut v = vec![*num, x, y];
-            v.sort();
-            return Some((v[0], v[1], v[2]));
+/// Lazy depth-first enumeration of k-sum solutions in lexicographic order.
+///
+/// `path` holds indices into `items` of the entries chosen so far and `cursor` is the next
+/// index to try at depth `path.len()`. Branches are pruned as soon as the remaining slots
+/// can't reach the target, even using the smallest or largest remaining value throughout.
+pub struct KSums {
+    items: Vec<(i64, usize)>,
+    max: i64,
+    k: usize,
+    target: i64,
+    path: Vec<usize>,
+    path_sum: i128,
+    cursor: usize,
+    done: bool,
+}
+
+impl KSums {
+    /// Drop the last chosen entry and move on to its successor. Return false when there is
+    /// nothing left to drop.
+    fn backtrack(&mut self) -> bool {
+        match self.path.pop() {
+            None => {
+                self.done = true;
+                false
+            },
+            Some(i) => {
+                self.path_sum -= self.items[i].0 as i128;
+                self.cursor = i + 1;
+                true
+            },
+        }
+    }
+}
+
+impl Iterator for KSums {
+    type Item = Vec<i64>;
+
+    fn next(&mut self) -> Option<Vec<i64>> {
+        while !self.done {
+            let slots = (self.k - self.path.len()) as i128;
+            let remaining = self.target as i128 - self.path_sum;
+            if slots == 0 {
+                let found = if remaining == 0 {
+                    Some(self.path.iter().map(|i| self.items[*i].0).collect())
+                } else {
+                    None
+                };
+                self.backtrack();
+                if found.is_some() {
+                    return found;
+                }
+                continue;
+            }
+            if self.cursor >= self.items.len() || slots * (self.max as i128) < remaining {
+                self.backtrack();
+                continue;
+            }
+            let (x, cap) = self.items[self.cursor];
+            if slots * (x as i128) > remaining {
+                // every later value is at least `x`, so this depth is exhausted
+                self.backtrack();
+                continue;
+            }
+            let used = self.path.iter().rev().take_while(|i| **i == self.cursor).count();
+            if used >= cap {
+                self.cursor += 1;
+                continue;
+            }
+            self.path.push(self.cursor);
+            self.path_sum += x as i128;
+        }
+        None
+    }
+}
+
+/// Count the tuples returned by `k_sums` without materializing them.
+///
+/// Dynamic programming over the entries, tracking the number of ways to reach each
+/// (partial sum, tuple length) pair. If no entry is negative, partial sums above `sum` are
+/// dropped along the way.
+fn count_k_sums(nums: &Multiset, k: usize, sum: i64, mode: SumMode) -> u128 {
+    let items = capped_items(nums, k, mode);
+    let non_negative = items.iter().all(|(x, _)| *x >= 0);
+    let mut ways: HashMap<(i128, usize), u128> = HashMap::new();
+    ways.insert((0, 0), 1);
+    for (x, cap) in items.iter() {
+        let mut next = ways.clone();
+        for ((partial, len), n) in ways.iter() {
+            for m in 1..=(*cap).min(k - len) {
+                let s = partial + (m as i128) * (*x as i128);
+                if non_negative && s > sum as i128 {
+                    break;
+                }
+                *next.entry((s, len + m)).or_insert(0) += n;
+            }
+        }
+        ways = next;
+    }
+    ways.get(&(sum as i128, k)).cloned().unwrap_or(0)
+}
+
+/// Find a non-decreasing `k`-tuple of entries in the multiset whose sum is as large as
+/// possible without exceeding `bound`, combining entries according to `mode`.
+///
+/// Uses the same smallest-entry-first recursion as `find_k_sum`, in O(n^(k-1)) time.
+fn find_k_sum_at_most(nums: &Multiset, k: usize, bound: i64, mode: SumMode) -> Option<Vec<i64>> {
+    let items = capped_items(nums, k, mode);
+    k_sum_at_most(&items, 0, k, bound as i128).map(|(_, tuple)| tuple)
+}
+
+/// Return the largest sum not exceeding `bound` of a non-decreasing `k`-tuple from the
+/// ascending `(value, cap)` slice `items`, along with the tuple. `taken` copies of `items[0]`
+/// have already been used by the caller.
+fn k_sum_at_most(items: &[(i64, usize)], taken: usize, k: usize, bound: i128) -> Option<(i128, Vec<i64>)> {
+    let avail = |i: usize| if i == 0 { items[0].1 - taken } else { items[i].1 };
+    match k {
+        0 => if bound >= 0 { Some((0, Vec::new())) } else { None },
+        1 => {
+            let end = items.partition_point(|(x, _)| *x as i128 <= bound);
+            (0..end).rev()
+                .find(|i| avail(*i) > 0)
+                .map(|i| (items[i].0 as i128, vec![items[i].0]))
+        },
+        2 => {
+            if items.is_empty() {
+                return None;
+            }
+            let mut best: Option<(i128, Vec<i64>)> = None;
+            let (mut lo, mut hi) = (0, items.len() - 1);
+            while lo <= hi {
+                let s = items[lo].0 as i128 + items[hi].0 as i128;
+                if s > bound {
+                    if hi == 0 {
+                        break;
+                    }
+                    hi -= 1;
+                } else {
+                    if (lo < hi || avail(lo) >= 2) && best.as_ref().is_none_or(|(b, _)| s > *b) {
+                        best = Some((s, vec![items[lo].0, items[hi].0]));
+                    }
+                    lo += 1;
+                }
+            }
+            best
+        },
+        _ => {
+            let mut best: Option<(i128, Vec<i64>)> = None;
+            for (i, (x, _)) in items.iter().enumerate() {
+                let x_wide = *x as i128;
+                if x_wide * k as i128 > bound {
+                    // every later value is at least `x`
+                    break;
+                }
+                let left = avail(i);
+                if left == 0 {
+                    continue;
+                }
+                let rest = if left == 1 {
+                    k_sum_at_most(&items[i + 1..], 0, k - 1, bound - x_wide)
+                } else {
+                    let used = if i == 0 { taken + 1 } else { 1 };
+                    k_sum_at_most(&items[i..], used, k - 1, bound - x_wide)
+                };
+                if let Some((s, mut tuple)) = rest {
+                    if best.as_ref().is_none_or(|(b, _)| s + x_wide > *b) {
+                        tuple.insert(0, *x);
+                        best = Some((s + x_wide, tuple));
+                    }
+                }
+            }
+            best
+        },
+    }
+}
+
+/// Largest span of reachable sums (total of positive entries minus total of negative
+/// entries) for which `find_subset_sum` uses dynamic programming.
+const SUBSET_DP_MAX_RANGE: i128 = 1 << 22;
+
+/// Largest number of entries for which `find_subset_sum` falls back to meet-in-the-middle.
+const SUBSET_MITM_MAX_ENTRIES: usize = 40;
+
+/// Find a subset of the entries in the multiset, of any size, that adds to `sum`. Each entry
+/// is used at most once, but equal entries on different lines are distinct. The subset is
+/// returned in non-decreasing order.
+///
+/// When the span of reachable sums is at most `SUBSET_DP_MAX_RANGE` this is a 0/1 knapsack
+/// over that span. Otherwise the entries are split in two halves whose subset sums are matched
+/// against each other, which requires at most `SUBSET_MITM_MAX_ENTRIES` entries.
+fn find_subset_sum(nums: &Multiset, sum: i64) -> Result<Option<Vec<i64>>, &'static str> {
+    let entries: Vec<i64> = nums.iter()
+        .flat_map(|(x, count)| std::iter::repeat_n(*x, *count))
+        .collect();
+    let total = |keep: fn(&i64) -> bool| entries.iter().filter(|x| keep(x)).map(|x| *x as i128).sum::<i128>();
+    let (lowest, highest) = (total(|x| *x < 0), total(|x| *x > 0));
+    if (sum as i128) < lowest || (sum as i128) > highest {
+        return Ok(None);
+    }
+    let mut result = if highest - lowest <= SUBSET_DP_MAX_RANGE {
+        // both totals lie within the span around 0, so they fit in an i64
+        subset_sum_dp(&entries, lowest as i64, highest as i64, sum)
+    } else if entries.len() <= SUBSET_MITM_MAX_ENTRIES {
+        subset_sum_mitm(&entries, sum)
+    } else {
+        return Err("more than 40 entries with too wide a range of sums for subset sum");
+    };
+    if let Some(subset) = result.as_mut() {
+        subset.sort();
+    }
+    Ok(result)
+}
+
+/// 0/1 knapsack over sums in `lowest..=highest`, remembering for each reachable sum the first
+/// entry that reached it so that the subset can be reconstructed.
+fn subset_sum_dp(entries: &[i64], lowest: i64, highest: i64, sum: i64) -> Option<Vec<i64>> {
+    let offset = |s: i64| (s - lowest) as usize;
+    // reached_by[offset(s)] is the index of the entry that first reached `s`; sums reached
+    // by an entry only depend on entries with a smaller index.
+    let mut reached_by: Vec<Option<usize>> = vec![None; offset(highest) + 1];
+    reached_by[offset(0)] = Some(usize::MAX);
+    for (i, x) in entries.iter().enumerate() {
+        // visit sums away from `x` first so that each entry is used at most once
+        let update = |s: i64, reached_by: &mut Vec<Option<usize>>| {
+            if reached_by[offset(s)].is_none() && reached_by[offset(s - x)].is_some() {
+                reached_by[offset(s)] = Some(i);
+            }
+        };
+        if *x >= 0 {
+            for s in (lowest + x..=highest).rev() {
+                update(s, &mut reached_by);
+            }
+        } else {
+            for s in lowest..=highest + x {
+                update(s, &mut reached_by);
+            }
+        }
+    }
+
+    reached_by[offset(sum)]?;
+    let mut subset = Vec::new();
+    let mut s = sum;
+    while s != 0 {
+        let i = reached_by[offset(s)].expect("broken subset sum chain");
+        subset.push(entries[i]);
+        s -= entries[i];
+    }
+    Some(subset)
+}
+
+/// Match subset sums of the two halves of `entries` against each other, taking sums as
+/// `i128` so that large entries can't overflow.
+fn subset_sum_mitm(entries: &[i64], sum: i64) -> Option<Vec<i64>> {
+    let (left, right) = entries.split_at(entries.len() / 2);
+    let subset_sums = |half: &[i64]| -> Vec<(i128, u64)> {
+        (0..1u64 << half.len())
+            .map(|mask| {
+                let s = half.iter().enumerate()
+                    .filter(|(i, _)| mask & (1 << i) != 0)
+                    .map(|(_, x)| *x as i128)
+                    .sum();
+                (s, mask)
+            })
+            .collect()
+    };
+    let pick = |half: &[i64], mask: u64| -> Vec<i64> {
+        half.iter().enumerate()
+            .filter(|(i, _)| mask & (1 << i) != 0)
+            .map(|(_, x)| *x)
+            .collect()
+    };
+
+    let left_sums: HashMap<i128, u64> = subset_sums(left).into_iter().collect();
+    for (s, right_mask) in subset_sums(right) {
+        if let Some(left_mask) = left_sums.get(&(sum as i128 - s)) {
+            let mut subset = pick(left, *left_mask);
+            subset.extend(pick(right, right_mask));
+            return Some(subset);
         }
     }
     None
 }
 
+/// Number of leading elements equal to the first one.
+fn run_length<'a, I>(mut iter: I) -> usize
+    where I: Iterator<Item = &'a i64> {
+    match iter.next() {
+        None => 0,
+        Some(first) => 1 + iter.take_while(|x| *x == first).count(),
+    }
+}
+
+/// Call `f` on every non-decreasing `k`-tuple drawn from the ascending `(value, cap)` slice
+/// `items`, using each value at most `cap` times, in lexicographic order. Enumeration stops
+/// early when `f` returns false.
+fn for_each_multiset<F>(items: &[(i64, usize)], k: usize, f: &mut F)
+    where F: FnMut(&[i64]) -> bool {
+    fn go<F>(items: &[(i64, usize)], start: usize, taken: usize, k: usize, acc: &mut Vec<i64>, f: &mut F) -> bool
+        where F: FnMut(&[i64]) -> bool {
+        if acc.len() == k {
+            return f(acc);
+        }
+        for (i, (x, cap)) in items.iter().enumerate().skip(start) {
+            let used = if i == start { taken } else { 0 };
+            if used >= *cap {
+                continue;
+            }
+            acc.push(*x);
+            let keep_going = go(items, i, used + 1, k, acc, f);
+            acc.pop();
+            if !keep_going {
+                return false;
+            }
+        }
+        true
+    }
+    go(items, 0, 0, k, &mut Vec::with_capacity(k), f);
+}
+
 #[cfg(test)]
 mod test_d1 {
-    use std::collections::BTreeSet;
     use super::*;
 
+    /// Build a multiset of expense report entries
+    fn multiset(nums: &[i64]) -> Multiset {
+        let mut result = Multiset::new();
+        for num in nums {
+            *result.entry(*num).or_insert(0) += 1;
+        }
+        result
+    }
+
     #[test]
     fn test_pair_2020() {
-        let nums = [1721, 979, 366, 299, 675, 1456].iter().cloned().collect::<BTreeSet<i64>>();
-        assert_eq!(find_sum_pair(&nums, 2020), Some((299, 1721)));
+        let nums = multiset(&[1721, 979, 366, 299, 675, 1456]);
+        assert_eq!(find_sum_pair(&nums, 2020, SumMode::RepeatValues), Some((299, 1721)));
     }
 
     #[test]
     fn test_coincident_pair_2020() {
-        let nums = [1010, 42].iter().cloned().collect::<BTreeSet<i64>>();
-        assert_eq!(find_sum_pair(&nums, 2020), Some((1010, 1010)));
+        let nums = multiset(&[1010, 42]);
+        assert_eq!(find_sum_pair(&nums, 2020, SumMode::RepeatValues), Some((1010, 1010)));
     }
 
     #[test]
     fn test_triple_2020() {
-        let nums = [1721, 979, 366, 299, 675, 1456].iter().cloned().collect::<BTreeSet<i64>>();
-        assert_eq!(find_sum_triple(&nums, 2020), Some((366, 675, 979)));
+        let nums = multiset(&[1721, 979, 366, 299, 675, 1456]);
+        assert_eq!(find_sum_triple(&nums, 2020, SumMode::RepeatValues), Some((366, 675, 979)));
+    }
+
+    #[test]
+    fn test_k_sum_small_k() {
+        let nums = multiset(&[1721, 979, 366, 299, 675, 1456]);
+        assert_eq!(find_k_sum(&nums, 0, 0, SumMode::RepeatValues), Some(vec![]));
+        assert_eq!(find_k_sum(&nums, 0, 2020, SumMode::RepeatValues), None);
+        assert_eq!(find_k_sum(&nums, 1, 979, SumMode::RepeatValues), Some(vec![979]));
+        assert_eq!(find_k_sum(&nums, 1, 2020, SumMode::RepeatValues), None);
+        assert_eq!(find_k_sum(&nums, 2, 2020, SumMode::RepeatValues), Some(vec![299, 1721]));
+        assert_eq!(find_k_sum(&nums, 3, 2020, SumMode::RepeatValues), Some(vec![366, 675, 979]));
+        assert_eq!(find_k_sum(&Multiset::new(), 2, 2020, SumMode::RepeatValues), None);
+    }
+
+    #[test]
+    fn test_k_sum_mitm() {
+        let nums = multiset(&[1721, 979, 366, 299, 675, 1456]);
+        // several 4-tuples share this sum, so only check the shape of the answer
+        let four = find_k_sum(&nums, 4, 299 + 366 + 675 + 979, SumMode::RepeatValues).unwrap();
+        assert_eq!(four.iter().sum::<i64>(), 299 + 366 + 675 + 979);
+        assert!(four.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(find_k_sum(&nums, 5, 5 * 299, SumMode::RepeatValues), Some(vec![299; 5]));
+        assert_eq!(find_k_sum(&nums, 5, 1, SumMode::RepeatValues), None);
+    }
+
+    #[test]
+    fn test_k_sum_large_entries() {
+        let nums = multiset(&[i64::MAX, i64::MAX, i64::MIN, 1, -1]);
+        assert_eq!(find_k_sum(&nums, 2, -1, SumMode::DistinctIndices), Some(vec![i64::MIN, i64::MAX]));
+        assert_eq!(find_k_sum(&nums, 3, -1, SumMode::DistinctIndices), None);
+        assert_eq!(find_k_sum(&nums, 4, -1, SumMode::DistinctIndices), Some(vec![i64::MIN, -1, 1, i64::MAX]));
+        assert_eq!(find_k_sum(&nums, 2, i64::MAX, SumMode::DistinctIndices), None);
+        assert_eq!(find_k_sum(&nums, 4, i64::MAX, SumMode::DistinctIndices), Some(vec![i64::MIN, 1, i64::MAX, i64::MAX]));
+    }
+
+    #[test]
+    fn test_k_sum_strategies_agree() {
+        let nums: Multiset = (1..40).map(|x| (x * 7 % 53, (x % 3) as usize)).collect();
+        for mode in [SumMode::DistinctIndices, SumMode::RepeatValues, SumMode::DistinctValues].iter() {
+            for k in 2..6 {
+                let items = capped_items(&nums, k, *mode);
+                for target in 0..200 {
+                    let by_pointer = k_sum_two_pointer(&items, 0, k, target as i128);
+                    let by_mitm = k_sum_mitm(&items, k, target as i128);
+                    assert_eq!(
+                        by_pointer.is_some(), by_mitm.is_some(),
+                        "mode = {:?}, k = {}, target = {}", mode, k, target
+                    );
+                    if let Some(v) = by_mitm {
+                        assert_eq!(v.iter().sum::<i64>(), target);
+                        assert!(v.windows(2).all(|w| w[0] <= w[1]));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sum_modes_duplicate_entries() {
+        // 1010 occurs twice, 500 once
+        let nums = multiset(&[1010, 500, 1010, 42]);
+        assert_eq!(find_k_sum(&nums, 2, 2020, SumMode::DistinctIndices), Some(vec![1010, 1010]));
+        assert_eq!(find_k_sum(&nums, 2, 2020, SumMode::RepeatValues), Some(vec![1010, 1010]));
+        assert_eq!(find_k_sum(&nums, 2, 2020, SumMode::DistinctValues), None);
+
+        assert_eq!(find_k_sum(&nums, 2, 1000, SumMode::DistinctIndices), None);
+        assert_eq!(find_k_sum(&nums, 2, 1000, SumMode::RepeatValues), Some(vec![500, 500]));
+        assert_eq!(find_k_sum(&nums, 2, 1000, SumMode::DistinctValues), None);
+
+        assert_eq!(find_k_sum(&nums, 3, 3030, SumMode::DistinctIndices), None);
+        assert_eq!(find_k_sum(&nums, 3, 3030, SumMode::RepeatValues), Some(vec![1010; 3]));
+        assert_eq!(find_k_sum(&nums, 4, 2562, SumMode::DistinctIndices), Some(vec![42, 500, 1010, 1010]));
+        assert_eq!(find_k_sum(&nums, 4, 2562, SumMode::DistinctValues), None);
+    }
+
+    #[test]
+    fn test_sum_modes_agree_on_sets() {
+        let nums = multiset(&[1721, 979, 366, 299, 675, 1456]);
+        assert_eq!(find_k_sum(&nums, 2, 2020, SumMode::DistinctIndices), Some(vec![299, 1721]));
+        assert_eq!(find_k_sum(&nums, 3, 2020, SumMode::DistinctValues), Some(vec![366, 675, 979]));
+        // 1010 can't pair with itself when it occurs only once
+        let nums = multiset(&[1010, 42]);
+        assert_eq!(find_k_sum(&nums, 2, 2020, SumMode::DistinctIndices), None);
+    }
+
+    #[test]
+    fn test_all_k_sums() {
+        let nums = multiset(&[1721, 979, 366, 299, 675, 1456]);
+        assert_eq!(k_sums(&nums, 2, 2020, SumMode::DistinctIndices).collect::<Vec<_>>(), vec![vec![299, 1721]]);
+        assert_eq!(count_k_sums(&nums, 3, 2020, SumMode::DistinctIndices), 1);
+        assert_eq!(k_sums(&nums, 2, 1, SumMode::DistinctIndices).next(), None);
+
+        let nums = multiset(&[1, 2, 3, 4, 5, 5]);
+        assert_eq!(
+            k_sums(&nums, 2, 6, SumMode::RepeatValues).collect::<Vec<_>>(),
+            vec![vec![1, 5], vec![2, 4], vec![3, 3]]
+        );
+        assert_eq!(
+            k_sums(&nums, 3, 10, SumMode::DistinctIndices).collect::<Vec<_>>(),
+            vec![vec![1, 4, 5], vec![2, 3, 5]]
+        );
+        assert_eq!(
+            k_sums(&nums, 3, 11, SumMode::DistinctIndices).collect::<Vec<_>>(),
+            vec![vec![1, 5, 5], vec![2, 4, 5]]
+        );
+        assert_eq!(
+            k_sums(&nums, 3, 11, SumMode::DistinctValues).collect::<Vec<_>>(),
+            vec![vec![2, 4, 5]]
+        );
+        assert_eq!(k_sums(&nums, 0, 0, SumMode::RepeatValues).collect::<Vec<_>>(), vec![Vec::<i64>::new()]);
+    }
+
+    #[test]
+    fn test_k_sums_lazy() {
+        let nums = multiset(&(1..=1000).collect::<Vec<i64>>());
+        let mut solutions = k_sums(&nums, 3, 2020, SumMode::DistinctValues);
+        // 999 + 1000 is the largest sum of two distinct values, so 21 is the smallest first entry
+        assert_eq!(solutions.next(), Some(vec![21, 999, 1000]));
+        assert_eq!(solutions.next(), Some(vec![22, 998, 1000]));
+    }
+
+    #[test]
+    fn test_count_matches_enumeration() {
+        let nums = multiset(&[-3, -1, 0, 0, 2, 2, 2, 5, 7, 7, 11]);
+        for mode in [SumMode::DistinctIndices, SumMode::RepeatValues, SumMode::DistinctValues].iter() {
+            for k in 0..5 {
+                for target in -10..30 {
+                    let all = k_sums(&nums, k, target, *mode).collect::<Vec<_>>();
+                    assert_eq!(count_k_sums(&nums, k, target, *mode), all.len() as u128);
+                    let mut sorted = all.clone();
+                    sorted.sort();
+                    sorted.dedup();
+                    assert_eq!(all, sorted);
+                    for tuple in all.iter() {
+                        assert_eq!(tuple.iter().sum::<i64>(), target);
+                        assert!(tuple.windows(2).all(|w| w[0] <= w[1]));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_k_sum_at_most() {
+        let nums = multiset(&[1721, 979, 366, 299, 675, 1456]);
+        assert_eq!(find_k_sum_at_most(&nums, 2, 2020, SumMode::DistinctIndices), Some(vec![299, 1721]));
+        assert_eq!(find_k_sum_at_most(&nums, 2, 2019, SumMode::DistinctIndices), Some(vec![366, 1456]));
+        assert_eq!(find_k_sum_at_most(&nums, 2, 600, SumMode::DistinctIndices), None);
+        assert_eq!(find_k_sum_at_most(&nums, 2, 600, SumMode::RepeatValues), Some(vec![299, 299]));
+        assert_eq!(find_k_sum_at_most(&nums, 1, 1000, SumMode::DistinctIndices), Some(vec![979]));
+        let large = multiset(&[i64::MAX, i64::MAX, -3]);
+        assert_eq!(find_k_sum_at_most(&large, 2, i64::MAX, SumMode::DistinctIndices), Some(vec![-3, i64::MAX]));
+        assert_eq!(find_k_sum_at_most(&large, 3, i64::MAX, SumMode::DistinctIndices), None);
+    }
+
+    #[test]
+    fn test_k_sum_at_most_matches_brute_force() {
+        let nums = multiset(&[-4, 1, 1, 3, 8, 8, 8, 13, 21]);
+        for mode in [SumMode::DistinctIndices, SumMode::RepeatValues, SumMode::DistinctValues].iter() {
+            for k in 0..5 {
+                for bound in -20..60 {
+                    let best = (-100..=bound).rev().find(|s| count_k_sums(&nums, k, *s, *mode) > 0);
+                    let found = find_k_sum_at_most(&nums, k, bound, *mode);
+                    assert_eq!(found.as_ref().map(|v| v.iter().sum::<i64>()), best, "k = {}, bound = {}", k, bound);
+                    if let Some(v) = found {
+                        assert!(v.windows(2).all(|w| w[0] <= w[1]));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_subset_sum() {
+        let nums = multiset(&[1721, 979, 366, 299, 675, 1456]);
+        let subset = find_subset_sum(&nums, 2020).unwrap().unwrap();
+        assert!(subset == vec![299, 1721] || subset == vec![366, 675, 979]);
+        assert_eq!(find_subset_sum(&nums, 0), Ok(Some(vec![])));
+        assert_eq!(find_subset_sum(&nums, 1), Ok(None));
+        assert_eq!(find_subset_sum(&nums, 299 + 366 + 675), Ok(Some(vec![299, 366, 675])));
+
+        // 1010 only pairs with itself if it occurs twice
+        assert_eq!(find_subset_sum(&multiset(&[1010, 42]), 2020), Ok(None));
+        assert_eq!(find_subset_sum(&multiset(&[1010, 42, 1010]), 2020), Ok(Some(vec![1010, 1010])));
+        assert_eq!(find_subset_sum(&multiset(&[-5, 3, 9]), 4), Ok(Some(vec![-5, 9])));
+        assert_eq!(find_subset_sum(&multiset(&[-5, 3, 9]), -2), Ok(Some(vec![-5, 3])));
+    }
+
+    #[test]
+    fn test_subset_sum_strategies_agree() {
+        let entries = [-7, -3, 2, 2, 5, 11, 13, 13, 20];
+        let lowest = -10;
+        let highest = 66;
+        for target in lowest - 2..=highest + 2 {
+            let by_dp = if target < lowest || target > highest {
+                None
+            } else {
+                subset_sum_dp(&entries, lowest, highest, target)
+            };
+            let by_mitm = subset_sum_mitm(&entries, target);
+            assert_eq!(by_dp.is_some(), by_mitm.is_some(), "target = {}", target);
+            for subset in by_dp.iter().chain(by_mitm.iter()) {
+                assert_eq!(subset.iter().sum::<i64>(), target);
+                let mut remaining = multiset(&entries);
+                for x in subset {
+                    let count = remaining.get_mut(x).unwrap();
+                    assert!(*count > 0);
+                    *count -= 1;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_subset_sum_wide_range() {
+        let nums = multiset(&[1 << 40, 3, 1 << 41, 5, (1 << 40) + 7]);
+        assert_eq!(find_subset_sum(&nums, (1 << 41) + 10), Ok(Some(vec![3, 1 << 40, (1 << 40) + 7])));
+        assert_eq!(find_subset_sum(&nums, 4), Ok(None));
+        let many = multiset(&(0..50).map(|i| 1i64 << i).collect::<Vec<_>>());
+        assert_eq!(find_subset_sum(&many, 12345), Err("more than 40 entries with too wide a range of sums for subset sum"));
+        // the totals of the entries don't fit in an i64
+        let large = multiset(&[i64::MAX, 1, i64::MAX]);
+        assert_eq!(find_subset_sum(&large, 2020), Ok(None));
+        assert_eq!(find_subset_sum(&large, i64::MAX), Ok(Some(vec![i64::MAX])));
+        assert_eq!(find_subset_sum(&multiset(&[i64::MIN, -1, 5]), 4), Ok(Some(vec![-1, 5])));
+        let extremes = multiset(&[i64::MAX - 5, i64::MIN, 5]);
+        assert_eq!(find_subset_sum(&extremes, -1), Ok(Some(vec![i64::MIN, 5, i64::MAX - 5])));
+        assert_eq!(find_subset_sum(&extremes, i64::MAX), Ok(Some(vec![5, i64::MAX - 5])));
+        assert_eq!(find_subset_sum(&extremes, i64::MIN + 1), Ok(None));
+    }
+
+    #[test]
+    fn test_checked_product() {
+        assert_eq!(checked_product(&[1721, 299]), Ok(514579));
+        assert_eq!(checked_product(&[979, 366, 675]), Ok(241861950));
+        assert_eq!(checked_product(&[]), Ok(1));
+        assert_eq!(checked_product(&[-3, 7]), Ok(-21));
+        assert_eq!(checked_product(&[i64::MAX, 1]), Ok(i64::MAX));
+        assert_eq!(
+            checked_product(&[i64::MAX, 2]),
+            Err(ProductError::Overflow(vec![i64::MAX, 2]))
+        );
+        // 2020^6 ~ 6.8e19 overflows an i64 (max ~ 9.2e18)
+        assert!(checked_product(&[2020; 6]).is_err());
+    }
+
+    #[test]
+    fn test_wide_product() {
+        assert_eq!(wide_product(&[2020; 6]), Ok(2020i128.pow(6)));
+        assert_eq!(wide_product(&[i64::MIN, i64::MIN]), Ok((i64::MIN as i128) * (i64::MIN as i128)));
+        assert_eq!(wide_product(&[979, 366, 675]).map(|p| p as i64), checked_product(&[979, 366, 675]));
+        // 2020^12 ~ 4.6e39 overflows an i128 (max ~ 1.7e38)
+        assert_eq!(wide_product(&[2020; 12]), Err(ProductError::Overflow(vec![2020; 12])));
     }
 }
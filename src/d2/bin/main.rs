@@ -32,38 +32,312 @@
 /// 2-9 c: ccccccccc is invalid: both position 2 and position 9 contain c.
 /// How many passwords are valid according to the new interpretation of the policies?
 extern crate aoc_2020;
+extern crate unicode_normalization;
+extern crate unicode_segmentation;
 
+use std::collections::BTreeMap;
+use std::fmt;
 use std::str::FromStr;
+use aoc_2020::json;
+use aoc_2020::rng::Rng;
 use aoc_2020::util;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
 
+/// Error parsing or evaluating the password database.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PolicyError {
+    /// A line or policy doesn't match the expected format
+    Malformed(String),
+    /// No policy interpretation is registered under the name
+    UnknownPolicy(String),
+    /// An old policy's minimum count exceeds its maximum
+    EmptyRange { min: usize, max: usize },
+    /// A new policy refers to position 0; positions are 1-based
+    ZeroPosition,
+}
+
+/// The units that policies count and index passwords by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextUnits {
+    /// Unicode scalar values, i.e. Rust `char`s
+    CodePoints,
+    /// Extended grapheme clusters of the NFC normalized text, so that e.g. an
+    /// `e` followed by a combining acute accent is the same single letter as a
+    /// precomposed `é`
+    Graphemes,
+}
+
+impl TextUnits {
+    /// Split `text` into units
+    pub fn split(self, text: &str) -> Vec<String> {
+        match self {
+            TextUnits::CodePoints => text.chars().map(String::from).collect(),
+            TextUnits::Graphemes => text.nfc().collect::<String>()
+                .graphemes(true)
+                .map(String::from)
+                .collect(),
+        }
+    }
+
+    /// Count the units in `text`
+    pub fn count(self, text: &str) -> usize {
+        match self {
+            TextUnits::CodePoints => text.chars().count(),
+            TextUnits::Graphemes => text.nfc().collect::<String>().graphemes(true).count(),
+        }
+    }
+}
+
+/// An interpretation of the `N-M L` policies in the password database.
+pub trait PasswordPolicy {
+    /// Parse patterns of the form: `N-M L` where N, M are non-negative
+    /// integers, and L is a character, into a policy.
+    fn parse(policy_str: &str) -> Result<Self, PolicyError> where Self: Sized;
+
+    /// Check the given password against the policy, returning the reason it
+    /// fails if it does.
+    fn check_detailed(&self, password: &str) -> Result<(), Violation>;
+
+    /// Return true iff. given password satisfies the policy
+    fn check(&self, password: &str) -> bool {
+        self.check_detailed(password).is_ok()
+    }
+
+    /// Describe the policy in words
+    fn explain(&self) -> String;
+
+    /// Return a random password from `gen` that satisfies the policy if
+    /// `satisfying` is true, or violates it otherwise. Return None if there is
+    /// none, or if sampling gave up because `gen` has too many passwords to
+    /// search them all.
+    ///
+    /// By default this is `rejection_sample`.
+    fn sample(&self, gen: &PasswordGenerator, satisfying: bool, rng: &mut Rng) -> Option<String> {
+        rejection_sample(self, gen, satisfying, rng)
+    }
+
+    /// Count and index passwords by `units`. Policies that don't look at
+    /// individual letters ignore this.
+    fn with_units(self, _units: TextUnits) -> Self where Self: Sized {
+        self
+    }
+}
+
+/// Number of random passwords `rejection_sample` tries
+const MAX_SAMPLE_ATTEMPTS: usize = 10_000;
+
+/// Largest number of passwords `rejection_sample` searches exhaustively
+const MAX_EXHAUSTIVE_PASSWORDS: usize = 1 << 20;
+
+/// Draw random passwords from `gen` until one satisfies (or violates) `policy`.
+/// After `MAX_SAMPLE_ATTEMPTS` tries, pick one at random from all of them if
+/// `gen` has at most `MAX_EXHAUSTIVE_PASSWORDS` passwords, or give up.
+fn rejection_sample<P>(policy: &P, gen: &PasswordGenerator, satisfying: bool, rng: &mut Rng) -> Option<String>
+    where P: PasswordPolicy + ?Sized {
+    let found = (0..MAX_SAMPLE_ATTEMPTS)
+        .map(|_| gen.random(rng))
+        .find(|password| policy.check(password) == satisfying);
+    if found.is_some() || gen.count().is_none_or(|n| n > MAX_EXHAUSTIVE_PASSWORDS) {
+        return found;
+    }
+    let matching = gen.exhaustive(policy, satisfying).count();
+    if matching == 0 {
+        return None;
+    }
+    gen.exhaustive(policy, satisfying).nth(rng.gen_range(0, matching))
+}
+
+/// Interpretation `P` with passwords and letters split into grapheme clusters.
+pub struct InGraphemes<P>(P);
+
+impl<P: PasswordPolicy> PasswordPolicy for InGraphemes<P> {
+    fn parse(policy_str: &str) -> Result<Self, PolicyError> {
+        Ok(InGraphemes(P::parse(policy_str)?.with_units(TextUnits::Graphemes)))
+    }
+
+    fn check_detailed(&self, password: &str) -> Result<(), Violation> {
+        self.0.check_detailed(password)
+    }
+
+    fn explain(&self) -> String {
+        format!("{} (counting grapheme clusters)", self.0.explain())
+    }
+
+    fn sample(&self, gen: &PasswordGenerator, satisfying: bool, rng: &mut Rng) -> Option<String> {
+        self.0.sample(gen, satisfying, rng)
+    }
+}
+
+/// The new interpretation in strict mode, where positions past the end of a
+/// password are a violation, see `NewPolicy::strict`.
+pub struct StrictPositions(NewPolicy);
+
+impl PasswordPolicy for StrictPositions {
+    fn parse(policy_str: &str) -> Result<Self, PolicyError> {
+        Ok(StrictPositions(NewPolicy::parse(policy_str)?.strict(true)))
+    }
+
+    fn check_detailed(&self, password: &str) -> Result<(), Violation> {
+        self.0.check_detailed(password)
+    }
+
+    fn explain(&self) -> String {
+        format!("{}, within the password", self.0.explain())
+    }
+
+    fn sample(&self, gen: &PasswordGenerator, satisfying: bool, rng: &mut Rng) -> Option<String> {
+        self.0.sample(gen, satisfying, rng)
+    }
+
+    fn with_units(self, units: TextUnits) -> Self {
+        StrictPositions(self.0.with_units(units))
+    }
+}
+
+/// Reason a password fails a policy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// The letter occurs `found` times, outside of `min..=max`
+    Count { letter: String, found: usize, min: usize, max: usize },
+    /// Both positions contain the letter
+    BothPositions { letter: String, pos1: usize, pos2: usize },
+    /// Neither position contains the letter
+    NeitherPosition { letter: String, pos1: usize, pos2: usize },
+    /// In strict mode, a position lies past the end of the password
+    PositionOutOfRange { pos: usize, len: usize },
+    /// A composite rule isn't satisfied
+    Rule(String),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::Count { letter, found, min, max } =>
+                write!(f, "found {} '{}', need {}..={}", found, letter, min, max),
+            Violation::BothPositions { letter, pos1, pos2 } =>
+                write!(f, "positions {} and {} both contain '{}'", pos1, pos2, letter),
+            Violation::NeitherPosition { letter, pos1, pos2 } =>
+                write!(f, "neither position {} nor {} contains '{}'", pos1, pos2, letter),
+            Violation::PositionOutOfRange { pos, len } =>
+                write!(f, "position {} is past the end of the {} character password", pos, len),
+            Violation::Rule(rule) => write!(f, "`{}` is not satisfied", rule),
+        }
+    }
+}
+
+impl Violation {
+    /// Describe the kind of violation, without the details of the policy
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Violation::Count { found, min, .. } if found < min => "too few of the letter",
+            Violation::Count { .. } => "too many of the letter",
+            Violation::BothPositions { .. } => "letter in both positions",
+            Violation::NeitherPosition { .. } => "letter in neither position",
+            Violation::PositionOutOfRange { .. } => "position past the end",
+            Violation::Rule(_) => "rule not satisfied",
+        }
+    }
+}
+
+/// A password in the database that fails a policy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Failure {
+    /// 1-based line number in the database
+    pub line_num: usize,
+    pub password: String,
+    pub violation: Violation,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}: {}", self.line_num, self.password, self.violation)
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct OldPolicy {
-    letter: char,
+    /// A single unit of text, see `letter_units`
+    letter: String,
     min: usize,
     max: usize,
+    units: TextUnits,
 }
 
 impl OldPolicy {
-    pub fn new(letter: char, min: usize, max: usize) -> Self {
+    pub fn new(letter: char, min: usize, max: usize) -> Result<Self, PolicyError> {
+        OldPolicy::with_letter(letter.to_string(), min, max)
+    }
+
+    /// Like `new`, but the letter may be any grapheme cluster
+    fn with_letter(letter: String, min: usize, max: usize) -> Result<Self, PolicyError> {
         if min > max {
-            panic!("min > max for policy");
+            return Err(PolicyError::EmptyRange { min, max });
         }
-        OldPolicy {
-            letter: letter,
-            min: min,
-            max: max,
+        Ok(OldPolicy {
+            letter,
+            min,
+            max,
+            units: TextUnits::CodePoints,
+        }.with_units(TextUnits::CodePoints))
+    }
+}
+
+impl PasswordPolicy for OldPolicy {
+    fn parse(policy_str: &str) -> Result<Self, PolicyError> {
+        OldPolicy::from_str(policy_str)
+    }
+
+    fn check_detailed(&self, password: &str) -> Result<(), Violation> {
+        let count = match letter_char(&self.letter, self.units) {
+            Some(c) => password.chars().filter(|d| *d == c).count(),
+            None => self.units.split(password).iter().filter(|u| **u == self.letter).count(),
+        };
+        if self.min <= count && count <= self.max {
+            Ok(())
+        } else {
+            Err(Violation::Count { letter: self.letter.clone(), found: count, min: self.min, max: self.max })
         }
     }
 
-    /// Return true iff. given password satisfies the policy
-    pub fn check(&self, password: &str) -> bool {
-        let count = password.chars().filter(|c| *c == self.letter).count();
-        self.min <= count && count <= self.max
+    fn explain(&self) -> String {
+        format!("'{}' must occur {} to {} times", self.letter, self.min, self.max)
+    }
+
+    /// Pick a length and a number of occurrences of the letter consistent with
+    /// `satisfying`, then scatter that many letters among other characters.
+    fn sample(&self, gen: &PasswordGenerator, satisfying: bool, rng: &mut Rng) -> Option<String> {
+        let letter = match letter_char(&self.letter, self.units) {
+            Some(c) => c,
+            None => return rejection_sample(self, gen, satisfying, rng),
+        };
+        let has_letter = gen.alphabet.contains(&letter);
+        let has_other = gen.alphabet.iter().any(|c| *c != letter);
+        let mut shapes = Vec::new();
+        for len in gen.min_len..=gen.max_len {
+            for count in 0..=len {
+                let feasible = (count == 0 || has_letter) && (count == len || has_other);
+                if feasible && (self.min <= count && count <= self.max) == satisfying {
+                    shapes.push((len, count));
+                }
+            }
+        }
+        let (len, count) = *rng.choose(&shapes)?;
+        let mut cs: Vec<char> = (0..len)
+            .map(|i| if i < count { letter } else { gen.random_char_except(letter, rng) })
+            .collect();
+        rng.shuffle(&mut cs);
+        Some(cs.into_iter().collect())
+    }
+
+    fn with_units(mut self, units: TextUnits) -> Self {
+        self.units = letter_units(&mut self.letter, units);
+        self
     }
 }
 
 impl FromStr for OldPolicy {
-    // TODO: use a more expressive error type
-    type Err = ();
+    type Err = PolicyError;
 
     /// Parse patterns of the form: `N-M L` where N, M are non-negative
     /// integers, and L is a character, into a `Policy`.
@@ -71,35 +345,158 @@ impl FromStr for OldPolicy {
     /// Parsing is insensitive to whitespace before N and after L.
     fn from_str(policy_str: &str) -> Result<Self, Self::Err> {
         let (num1, num2, letter) = parse_prefix(policy_str)?;
-        Ok(OldPolicy::new(letter, num1, num2))
+        OldPolicy::with_letter(letter, num1, num2)
     }
 }
 
+#[derive(Clone, Debug)]
 pub struct NewPolicy {
-    letter: char,
+    /// A single unit of text, see `letter_units`
+    letter: String,
     pos1: usize,
     pos2: usize,
+    /// Reject passwords too short to have both positions, instead of treating
+    /// missing positions as not containing the letter
+    strict: bool,
+    units: TextUnits,
 }
 
 impl NewPolicy {
-    pub fn new(letter: char, pos1: usize, pos2: usize) -> Self {
-        NewPolicy {
-            letter: letter,
-            pos1: pos1,
-            pos2: pos2,
+    pub fn new(letter: char, pos1: usize, pos2: usize) -> Result<Self, PolicyError> {
+        NewPolicy::with_letter(letter.to_string(), pos1, pos2)
+    }
+
+    /// Like `new`, but the letter may be any grapheme cluster
+    fn with_letter(letter: String, pos1: usize, pos2: usize) -> Result<Self, PolicyError> {
+        if pos1 == 0 || pos2 == 0 {
+            return Err(PolicyError::ZeroPosition);
         }
+        Ok(NewPolicy {
+            letter,
+            pos1,
+            pos2,
+            strict: false,
+            units: TextUnits::CodePoints,
+        }.with_units(TextUnits::CodePoints))
     }
 
-    /// Return true iff. given password satisfies the *new* policy
-    pub fn check(&self, password: &str) -> bool {
-        let cs: Vec<char> = password.chars().collect();
-        (cs[self.pos1-1] == self.letter) ^ (cs[self.pos2-1] == self.letter)
+    /// Set whether positions past the end of a password are a violation
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Check a password of `len()` units, where `contains(pos)` says whether
+    /// the unit at 1-based position `pos` is the letter.
+    fn check_positions<L, C>(&self, len: L, contains: C) -> Result<(), Violation>
+        where L: Fn() -> usize, C: Fn(usize) -> bool {
+        let (pos1, pos2) = (self.pos1, self.pos2);
+        if self.strict {
+            let len = len();
+            if let Some(pos) = [pos1, pos2].iter().find(|pos| **pos > len) {
+                return Err(Violation::PositionOutOfRange { pos: *pos, len });
+            }
+        }
+        let letter = self.letter.clone();
+        match (contains(pos1), contains(pos2)) {
+            (true, true) => Err(Violation::BothPositions { letter, pos1, pos2 }),
+            (false, false) => Err(Violation::NeitherPosition { letter, pos1, pos2 }),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl PasswordPolicy for NewPolicy {
+    fn parse(policy_str: &str) -> Result<Self, PolicyError> {
+        NewPolicy::from_str(policy_str)
+    }
+
+    fn check_detailed(&self, password: &str) -> Result<(), Violation> {
+        // positions are 1-based; those past the end don't contain the letter
+        if let Some(c) = letter_char(&self.letter, self.units) {
+            return self.check_positions(|| password.chars().count(), |pos| password.chars().nth(pos - 1) == Some(c));
+        }
+        let units = self.units.split(password);
+        self.check_positions(|| units.len(), |pos| units.get(pos - 1) == Some(&self.letter))
+    }
+
+    fn explain(&self) -> String {
+        format!("exactly one of positions {} and {} must be '{}'", self.pos1, self.pos2, self.letter)
+    }
+
+    /// Pick a length and which of the two positions hold the letter consistent
+    /// with `satisfying`, then fill in the rest of the password at random.
+    fn sample(&self, gen: &PasswordGenerator, satisfying: bool, rng: &mut Rng) -> Option<String> {
+        let letter = match letter_char(&self.letter, self.units) {
+            Some(c) => c,
+            None => return rejection_sample(self, gen, satisfying, rng),
+        };
+        let has_letter = gen.alphabet.contains(&letter);
+        let has_other = gen.alphabet.iter().any(|c| *c != letter);
+        let mut shapes = Vec::new();
+        for len in gen.min_len..=gen.max_len {
+            for (at1, at2) in [(true, true), (true, false), (false, true), (false, false)].iter() {
+                let feasible = |pos: usize, at: bool| if at {
+                    has_letter && pos <= len
+                } else {
+                    has_other || pos > len
+                };
+                if !feasible(self.pos1, *at1) || !feasible(self.pos2, *at2)
+                    || (self.pos1 == self.pos2 && at1 != at2) {
+                    continue;
+                }
+                // only the two positions (and, in strict mode, the length) matter
+                let mut cs = vec![gen.alphabet[0]; len];
+                for (pos, at) in [(self.pos1, *at1), (self.pos2, *at2)].iter() {
+                    if *pos <= len {
+                        cs[pos - 1] = if *at { letter } else { gen.random_char_except(letter, rng) };
+                    }
+                }
+                if self.check(&cs.iter().collect::<String>()) == satisfying {
+                    shapes.push((len, *at1, *at2));
+                }
+            }
+        }
+        let (len, at1, at2) = *rng.choose(&shapes)?;
+        let mut cs: Vec<char> = (0..len).map(|_| *rng.choose(&gen.alphabet).unwrap()).collect();
+        for (pos, at) in [(self.pos1, at1), (self.pos2, at2)].iter() {
+            if *pos <= len {
+                cs[pos - 1] = if *at { letter } else { gen.random_char_except(letter, rng) };
+            }
+        }
+        Some(cs.into_iter().collect())
+    }
+
+    fn with_units(mut self, units: TextUnits) -> Self {
+        self.units = letter_units(&mut self.letter, units);
+        self
+    }
+}
+
+/// Return the units a policy with the given `letter` can use when asked to use
+/// `units`, normalizing the letter to match. Letters made of several code
+/// points can only be matched as grapheme clusters.
+fn letter_units(letter: &mut String, units: TextUnits) -> TextUnits {
+    if units == TextUnits::Graphemes || letter.chars().count() > 1 {
+        *letter = letter.nfc().collect();
+        TextUnits::Graphemes
+    } else {
+        TextUnits::CodePoints
+    }
+}
+
+/// Return the letter as a `char` if passwords built out of `char`s can be
+/// checked for it one `char` at a time.
+fn letter_char(letter: &str, units: TextUnits) -> Option<char> {
+    let mut chars = letter.chars();
+    match (chars.next(), chars.next(), units) {
+        (Some(c), None, TextUnits::CodePoints) => Some(c),
+        _ => None,
     }
 }
 
 impl FromStr for NewPolicy {
-    // TODO: use a more expressive error type
-    type Err = ();
+    type Err = PolicyError;
 
     /// Parse patterns of the form: `N-M L` where N, M are non-negative
     /// integers, and L is a character, into a `Policy`.
@@ -107,68 +504,797 @@ impl FromStr for NewPolicy {
     /// Parsing is insensitive to whitespace before N and after L.
     fn from_str(policy_str: &str) -> Result<Self, Self::Err> {
         let (num1, num2, letter) = parse_prefix(policy_str)?;
-        Ok(NewPolicy::new(letter, num1, num2))
+        NewPolicy::with_letter(letter, num1, num2)
+    }
+}
+
+/// Synthesizes passwords over an alphabet with lengths in a range, for building
+/// test fixtures.
+pub struct PasswordGenerator {
+    alphabet: Vec<char>,
+    min_len: usize,
+    max_len: usize,
+}
+
+impl PasswordGenerator {
+    /// Return None if `alphabet` is empty or the length range is empty (`min_len > max_len`).
+    pub fn new(alphabet: &str, min_len: usize, max_len: usize) -> Option<Self> {
+        let mut chars: Vec<char> = alphabet.chars().collect();
+        chars.sort_unstable();
+        chars.dedup();
+        if chars.is_empty() || min_len > max_len {
+            return None;
+        }
+        Some(PasswordGenerator { alphabet: chars, min_len, max_len })
+    }
+
+    /// Return a uniformly random character of the alphabet other than `c`.
+    /// Panics if the alphabet is just `c`.
+    fn random_char_except(&self, c: char, rng: &mut Rng) -> char {
+        let others: Vec<char> = self.alphabet.iter().cloned().filter(|d| *d != c).collect();
+        *rng.choose(&others).expect("alphabet has no other characters")
+    }
+
+    /// Return the number of passwords, or None if it overflows
+    pub fn count(&self) -> Option<usize> {
+        (self.min_len..=self.max_len)
+            .try_fold(0usize, |acc, len| acc.checked_add(self.alphabet.len().checked_pow(len as u32)?))
+    }
+
+    /// Return a random password, ignoring any policy
+    pub fn random(&self, rng: &mut Rng) -> String {
+        let len = rng.gen_range(self.min_len, self.max_len + 1);
+        (0..len).map(|_| *rng.choose(&self.alphabet).unwrap()).collect()
+    }
+
+    /// Iterate over every password, shortest first and in alphabetical order
+    /// for each length.
+    pub fn all(&self) -> AllPasswords<'_> {
+        AllPasswords { gen: self, indices: Some(vec![0; self.min_len]) }
+    }
+
+    /// Iterate over every password that satisfies `policy` if `satisfying` is
+    /// true, or violates it otherwise.
+    pub fn exhaustive<'a, P>(&'a self, policy: &'a P, satisfying: bool) -> impl Iterator<Item = String> + 'a
+        where P: PasswordPolicy + ?Sized {
+        self.all().filter(move |password| policy.check(password) == satisfying)
+    }
+}
+
+/// Iterator returned by `PasswordGenerator::all`, counting through passwords as
+/// digits in base `alphabet.len()`.
+pub struct AllPasswords<'a> {
+    gen: &'a PasswordGenerator,
+    /// Indices into the alphabet of the next password, or None when exhausted
+    indices: Option<Vec<usize>>,
+}
+
+impl<'a> Iterator for AllPasswords<'a> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let gen = self.gen;
+        let indices = self.indices.as_mut()?;
+        let password = indices.iter().map(|i| gen.alphabet[*i]).collect();
+        // increment the last digit that isn't maxed out, zeroing the ones after it
+        match indices.iter().rposition(|i| *i + 1 < gen.alphabet.len()) {
+            Some(pos) => {
+                indices[pos] += 1;
+                indices[pos + 1..].iter_mut().for_each(|i| *i = 0);
+            },
+            None if indices.len() < gen.max_len => {
+                let len = indices.len() + 1;
+                *indices = vec![0; len];
+            },
+            None => self.indices = None,
+        }
+        Some(password)
+    }
+}
+
+/// Generate a database of `n` lines `N-M L: password` over `gen`, each password
+/// satisfying its old policy with probability 1/2.
+fn generate_database(gen: &PasswordGenerator, n: usize, rng: &mut Rng) -> Vec<String> {
+    let mut lines = Vec::new();
+    while lines.len() < n {
+        let letter = *rng.choose(&gen.alphabet).unwrap();
+        let min = rng.gen_range(0, gen.max_len + 1);
+        let max = rng.gen_range(min, gen.max_len + 1).max(1);
+        let policy = OldPolicy::new(letter, min, max).unwrap();
+        // empty passwords can't be written to the database
+        if let Some(password) = policy.sample(gen, rng.gen_bool(0.5), rng).filter(|p| !p.is_empty()) {
+            lines.push(format!("{}-{} {}: {}", policy.min, policy.max, letter, password));
+        }
+    }
+    lines
+}
+
+/// Parse a database line into a boxed policy of some interpretation and a password
+pub type PolicyParser = fn(&str) -> Result<(Box<dyn PasswordPolicy>, String), PolicyError>;
+
+fn boxed_parser<P: PasswordPolicy + 'static>(line: &str) -> Result<(Box<dyn PasswordPolicy>, String), PolicyError> {
+    let (policy, word) = parse_line::<P>(line)?;
+    Ok((Box::new(policy), word))
+}
+
+/// Named interpretations of the policies in the password database, in registration order.
+pub struct PolicyRegistry(Vec<(&'static str, PolicyParser)>);
+
+impl PolicyRegistry {
+    pub fn new() -> Self {
+        PolicyRegistry(Vec::new())
+    }
+
+    /// Register interpretation `P` under `name`, replacing any interpretation already
+    /// registered under that name.
+    pub fn register<P: PasswordPolicy + 'static>(&mut self, name: &'static str) {
+        let parser: PolicyParser = boxed_parser::<P>;
+        match self.0.iter_mut().find(|(n, _)| *n == name) {
+            Some(entry) => entry.1 = parser,
+            None => self.0.push((name, parser)),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<PolicyParser> {
+        self.0.iter().find(|(n, _)| *n == name).map(|(_, parser)| *parser)
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.0.iter().map(|(n, _)| *n).collect()
+    }
+
+    /// Count the passwords in `lines` that satisfy each of the named interpretations, in a
+    /// single pass over the database. Fails if a name isn't registered or a line doesn't
+    /// parse.
+    pub fn count_valid<I, S>(&self, names: &[&str], lines: I) -> Result<Vec<usize>, PolicyError>
+        where I: IntoIterator<Item = S>, S: AsRef<str> {
+        let parsers = names.iter()
+            .map(|name| self.get(name).ok_or_else(|| PolicyError::UnknownPolicy(name.to_string())))
+            .collect::<Result<Vec<PolicyParser>, PolicyError>>()?;
+        let mut counts = vec![0; parsers.len()];
+        for line in lines {
+            for (parser, count) in parsers.iter().zip(counts.iter_mut()) {
+                let (policy, word) = parser(line.as_ref())?;
+                if policy.check(&word) {
+                    *count += 1;
+                }
+            }
+        }
+        Ok(counts)
+    }
+
+    /// List every password in `lines` that fails the named interpretation,
+    /// along with the reason it fails.
+    pub fn report<I, S>(&self, name: &str, lines: I) -> Result<Vec<Failure>, PolicyError>
+        where I: IntoIterator<Item = S>, S: AsRef<str> {
+        let parser = self.get(name).ok_or_else(|| PolicyError::UnknownPolicy(name.to_string()))?;
+        let mut failures = Vec::new();
+        for (idx, line) in lines.into_iter().enumerate() {
+            let (policy, password) = parser(line.as_ref())?;
+            if let Err(violation) = policy.check_detailed(&password) {
+                failures.push(Failure { line_num: idx + 1, password, violation });
+            }
+        }
+        Ok(failures)
+    }
+}
+
+impl Default for PolicyRegistry {
+    /// The registry of the two interpretations in the puzzle, "old" and "new"
+    fn default() -> Self {
+        let mut registry = PolicyRegistry::new();
+        registry.register::<OldPolicy>("old");
+        registry.register::<NewPolicy>("new");
+        registry
+    }
+}
+
+/// Aggregate statistics over a password database, comparing the "old" and "new"
+/// interpretations of a `PolicyRegistry`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub lines: usize,
+    /// Number of policies for each letter, normalized as the policies match it
+    pub letters: BTreeMap<String, usize>,
+    /// Number of policies `N-M` for each width `|M - N|`
+    pub range_widths: BTreeMap<usize, usize>,
+    /// Number of passwords of each length, in the units the policies count
+    pub lengths: BTreeMap<usize, usize>,
+    pub old_only: usize,
+    pub new_only: usize,
+    pub both: usize,
+    pub neither: usize,
+    /// Number of failures for each interpretation and `Violation::reason`
+    pub failures: BTreeMap<(&'static str, &'static str), usize>,
+}
+
+impl Stats {
+    /// Collect statistics with policies from `registry` that count and index
+    /// passwords by `units`.
+    pub fn collect<I, S>(registry: &PolicyRegistry, units: TextUnits, lines: I) -> Result<Self, PolicyError>
+        where I: IntoIterator<Item = S>, S: AsRef<str> {
+        let parsers = ["old", "new"].iter()
+            .map(|name| registry.get(name).map(|parser| (*name, parser))
+                .ok_or_else(|| PolicyError::UnknownPolicy(name.to_string())))
+            .collect::<Result<Vec<(&'static str, PolicyParser)>, PolicyError>>()?;
+        let mut stats = Stats::default();
+        for line in lines {
+            let line = line.as_ref();
+            let (policy_str, password) = split_line(line)?;
+            let (num1, num2, mut letter) = parse_prefix(policy_str)?;
+            let units = letter_units(&mut letter, units);
+            stats.lines += 1;
+            *stats.letters.entry(letter).or_insert(0) += 1;
+            *stats.range_widths.entry(num1.max(num2) - num1.min(num2)).or_insert(0) += 1;
+            *stats.lengths.entry(units.count(password)).or_insert(0) += 1;
+
+            let mut valid = Vec::new();
+            for (name, parser) in parsers.iter() {
+                let (policy, password) = parser(line)?;
+                match policy.check_detailed(&password) {
+                    Ok(()) => valid.push(true),
+                    Err(violation) => {
+                        *stats.failures.entry((name, violation.reason())).or_insert(0) += 1;
+                        valid.push(false);
+                    },
+                }
+            }
+            match (valid[0], valid[1]) {
+                (true, true) => stats.both += 1,
+                (true, false) => stats.old_only += 1,
+                (false, true) => stats.new_only += 1,
+                (false, false) => stats.neither += 1,
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Failures by interpretation and reason, most common first
+    pub fn most_common_failures(&self) -> Vec<(&'static str, &'static str, usize)> {
+        let mut failures: Vec<_> = self.failures.iter()
+            .map(|((name, reason), count)| (*name, *reason, *count))
+            .collect();
+        failures.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| (a.0, a.1).cmp(&(b.0, b.1))));
+        failures
+    }
+
+    /// Render as a plain text table
+    pub fn table(&self) -> String {
+        let mut out = String::new();
+        let mut row = |key: &str, value: usize| out.push_str(&format!("{:<44} {:>6}\n", key, value));
+        row("lines", self.lines);
+        row("valid old only", self.old_only);
+        row("valid new only", self.new_only);
+        row("valid both", self.both);
+        row("valid neither", self.neither);
+        for (name, reason, count) in self.most_common_failures() {
+            row(&format!("{} failure: {}", name, reason), count);
+        }
+        for (letter, count) in self.letters.iter() {
+            row(&format!("letter {}", letter), *count);
+        }
+        for (width, count) in self.range_widths.iter() {
+            row(&format!("range width {}", width), *count);
+        }
+        for (len, count) in self.lengths.iter() {
+            row(&format!("password length {}", len), *count);
+        }
+        out
+    }
+
+    /// Render as a JSON object
+    pub fn to_json(&self) -> String {
+        fn object<K: fmt::Display>(map: &BTreeMap<K, usize>) -> String {
+            let fields: Vec<String> = map.iter()
+                .map(|(key, count)| format!("{}: {}", json::quote(&key.to_string()), count))
+                .collect();
+            format!("{{{}}}", fields.join(", "))
+        }
+        let failures: Vec<String> = self.most_common_failures().iter()
+            .map(|(name, reason, count)| format!(
+                "{{\"policy\": {}, \"reason\": {}, \"count\": {}}}",
+                json::quote(name), json::quote(reason), count))
+            .collect();
+        format!(
+            "{{\"lines\": {}, \"old_only\": {}, \"new_only\": {}, \"both\": {}, \"neither\": {}, \
+             \"failures\": [{}], \"letters\": {}, \"range_widths\": {}, \"lengths\": {}}}",
+            self.lines, self.old_only, self.new_only, self.both, self.neither, failures.join(", "),
+            object(&self.letters), object(&self.range_widths), object(&self.lengths))
     }
 }
 
 /// Parse prefixes of the form `N-M L` where N, M are non-negative integers
-/// and L is a character.
-fn parse_prefix(pref: &str) -> Result<(usize, usize, char), ()> {
+/// and L is a character, or more generally a single grapheme cluster. The
+/// letter is normalized along with passwords if it is matched as a grapheme
+/// cluster, see `letter_units`.
+fn parse_prefix(pref: &str) -> Result<(usize, usize, String), PolicyError> {
+    let malformed = || PolicyError::Malformed(pref.to_string());
     let splits: Vec<&str> = pref.trim().split(' ').collect();
     if splits.len() != 2 {
-        return Err(());
+        return Err(malformed());
     }
     let min_max_split: Vec<&str> = splits[0].split('-').collect();
     if min_max_split.len() != 2 {
-        return Err(());
+        return Err(malformed());
     }
-    let num1 = min_max_split[0].parse::<usize>().map_err(|_e| ())?;
-    let num2 = min_max_split[1].parse::<usize>().map_err(|_e| ())?;
+    let num1 = min_max_split[0].parse::<usize>().map_err(|_e| malformed())?;
+    let num2 = min_max_split[1].parse::<usize>().map_err(|_e| malformed())?;
 
-    let letter = splits[1].parse::<char>().map_err(|_e| ())?;
-    Ok((num1, num2, letter))
+    let letter = splits[1];
+    if letter.graphemes(true).count() != 1 {
+        return Err(malformed());
+    }
+    Ok((num1, num2, letter.to_string()))
 }
 
-/// Parse lines of the form `1-3 a: password` into a (new or old) policy
-/// (before the :) and / a word (after the :).
-fn parse_line<F, T>(line: &str, prefix_parser: F) -> Result<(T, String), ()>
-    where F: Fn(&str) -> Result<T, ()> {
+/// Split lines of the form `1-3 a: password` into the policy (before the :)
+/// and the password (after the :).
+fn split_line(line: &str) -> Result<(&str, &str), PolicyError> {
     let splits: Vec<&str> = line.trim().split(':').collect();
     if splits.len() != 2 {
-        return Err(());
+        return Err(PolicyError::Malformed(line.to_string()));
     }
-    let policy = prefix_parser(splits[0])?;
-    let word = splits[1].trim().to_string();
+    let word = splits[1].trim();
     if word.is_empty() {
-        return Err(());
+        return Err(PolicyError::Malformed(line.to_string()));
+    }
+    Ok((splits[0], word))
+}
+
+/// Parse lines of the form `1-3 a: password` into a policy of interpretation
+/// `P` (before the :) and a word (after the :).
+fn parse_line<P: PasswordPolicy>(line: &str) -> Result<(P, String), PolicyError> {
+    let (policy_str, word) = split_line(line)?;
+    let policy = P::parse(policy_str)?;
+    Ok((policy, word.to_string()))
+}
+
+/// A set of characters that a rule counts or looks for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CharClass {
+    /// `'a'`
+    Letter(char),
+    /// `[abc]`
+    Set(Vec<char>),
+    Digit,
+    Lower,
+    Upper,
+    Alpha,
+    Any,
+}
+
+impl CharClass {
+    pub fn matches(&self, c: char) -> bool {
+        match self {
+            CharClass::Letter(l) => c == *l,
+            CharClass::Set(cs) => cs.contains(&c),
+            CharClass::Digit => c.is_ascii_digit(),
+            CharClass::Lower => c.is_lowercase(),
+            CharClass::Upper => c.is_uppercase(),
+            CharClass::Alpha => c.is_alphabetic(),
+            CharClass::Any => true,
+        }
     }
-    Ok((policy, word))
 }
 
+impl fmt::Display for CharClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CharClass::Letter(l) => write!(f, "'{}'", l),
+            CharClass::Set(cs) => write!(f, "[{}]", cs.iter().collect::<String>()),
+            CharClass::Digit => write!(f, "digit"),
+            CharClass::Lower => write!(f, "lower"),
+            CharClass::Upper => write!(f, "upper"),
+            CharClass::Alpha => write!(f, "alpha"),
+            CharClass::Any => write!(f, "any"),
+        }
+    }
+}
+
+/// A password rule compiled from the rule language:
+///
+/// ```text
+/// rule  := rule "or" rule | rule "and" rule | "not" rule | "(" rule ")"
+///        | "count" N-M class           -- between N and M characters in class
+///        | "at" N class                -- character at position N is in class
+///        | "one-of" N,M,... class      -- exactly one of the positions is in class
+///        | "min-length" N
+///        | "old" N-M L | "new" N-M L   -- the puzzle's policies
+/// class := 'L' | [LLL...] | digit | lower | upper | alpha | any
+/// ```
+///
+/// `not` binds tightest, then `and`, then `or`. Positions are 1-based and
+/// positions past the end of the password are not in any class. Counting a
+/// single letter and "exactly one of" two positions with a single letter are
+/// compiled to `OldPolicy` and `NewPolicy` respectively.
+#[derive(Clone, Debug)]
+pub enum Rule {
+    Old(OldPolicy),
+    New(NewPolicy),
+    Count { class: CharClass, min: usize, max: usize },
+    At { pos: usize, class: CharClass },
+    ExactlyOne { positions: Vec<usize>, class: CharClass },
+    MinLength(usize),
+    And(Vec<Rule>),
+    Or(Vec<Rule>),
+    Not(Box<Rule>),
+}
+
+impl Rule {
+    /// Build a counting rule, using `OldPolicy` when counting a single letter
+    pub fn count(class: CharClass, min: usize, max: usize) -> Result<Self, PolicyError> {
+        match class {
+            CharClass::Letter(letter) => Ok(Rule::Old(OldPolicy::new(letter, min, max)?)),
+            _ if min > max => Err(PolicyError::EmptyRange { min, max }),
+            _ => Ok(Rule::Count { class, min, max }),
+        }
+    }
+
+    /// Build an "exactly one of" rule, using `NewPolicy` for two positions and a
+    /// single letter
+    pub fn exactly_one(positions: Vec<usize>, class: CharClass) -> Result<Self, PolicyError> {
+        if positions.contains(&0) {
+            return Err(PolicyError::ZeroPosition);
+        }
+        match (positions.as_slice(), &class) {
+            (&[pos1, pos2], CharClass::Letter(letter)) =>
+                Ok(Rule::New(NewPolicy::new(*letter, pos1, pos2)?)),
+            _ => Ok(Rule::ExactlyOne { positions, class }),
+        }
+    }
+
+    /// Render `self` as an operand of `and`, `or` or `not`
+    fn fmt_operand(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rule::And(_) | Rule::Or(_) => write!(f, "({})", self),
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rule::Old(p) => write!(f, "old {}-{} {}", p.min, p.max, p.letter),
+            Rule::New(p) => write!(f, "new {}-{} {}", p.pos1, p.pos2, p.letter),
+            Rule::Count { class, min, max } => write!(f, "count {}-{} {}", min, max, class),
+            Rule::At { pos, class } => write!(f, "at {} {}", pos, class),
+            Rule::ExactlyOne { positions, class } => {
+                let positions: Vec<String> = positions.iter().map(|p| p.to_string()).collect();
+                write!(f, "one-of {} {}", positions.join(","), class)
+            },
+            Rule::MinLength(n) => write!(f, "min-length {}", n),
+            Rule::And(rules) | Rule::Or(rules) => {
+                let op = if let Rule::And(_) = self { " and " } else { " or " };
+                for (i, rule) in rules.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "{}", op)?;
+                    }
+                    rule.fmt_operand(f)?;
+                }
+                Ok(())
+            },
+            Rule::Not(rule) => {
+                write!(f, "not ")?;
+                rule.fmt_operand(f)
+            },
+        }
+    }
+}
+
+impl PasswordPolicy for Rule {
+    fn parse(rule_str: &str) -> Result<Self, PolicyError> {
+        let mut parser = RuleParser::new(rule_str)?;
+        let rule = parser.parse_or()?;
+        match parser.next() {
+            None => Ok(rule),
+            Some(tok) => Err(parser.error(&format!("unexpected '{}'", tok))),
+        }
+    }
+
+    fn check_detailed(&self, password: &str) -> Result<(), Violation> {
+        let unsatisfied = || Err(Violation::Rule(self.to_string()));
+        let cs: Vec<char> = password.chars().collect();
+        let at = |pos: usize, class: &CharClass| cs.get(pos - 1).is_some_and(|c| class.matches(*c));
+        match self {
+            Rule::Old(p) => p.check_detailed(password),
+            Rule::New(p) => p.check_detailed(password),
+            Rule::Count { class, min, max } => {
+                let count = cs.iter().filter(|c| class.matches(**c)).count();
+                if *min <= count && count <= *max { Ok(()) } else { unsatisfied() }
+            },
+            Rule::At { pos, class } => if at(*pos, class) { Ok(()) } else { unsatisfied() },
+            Rule::ExactlyOne { positions, class } => {
+                if positions.iter().filter(|pos| at(**pos, class)).count() == 1 {
+                    Ok(())
+                } else {
+                    unsatisfied()
+                }
+            },
+            Rule::MinLength(n) => if cs.len() >= *n { Ok(()) } else { unsatisfied() },
+            Rule::And(rules) => rules.iter().try_for_each(|rule| rule.check_detailed(password)),
+            Rule::Or(rules) => if rules.iter().any(|rule| rule.check(password)) { Ok(()) } else { unsatisfied() },
+            Rule::Not(rule) => if rule.check(password) { unsatisfied() } else { Ok(()) },
+        }
+    }
+
+    fn explain(&self) -> String {
+        self.to_string()
+    }
+
+    /// Only letters of the `old` and `new` forms are affected; character
+    /// classes always match single code points.
+    fn with_units(self, units: TextUnits) -> Self {
+        match self {
+            Rule::Old(p) => Rule::Old(p.with_units(units)),
+            Rule::New(p) => Rule::New(p.with_units(units)),
+            Rule::And(rules) => Rule::And(rules.into_iter().map(|rule| rule.with_units(units)).collect()),
+            Rule::Or(rules) => Rule::Or(rules.into_iter().map(|rule| rule.with_units(units)).collect()),
+            Rule::Not(rule) => Rule::Not(Box::new(rule.with_units(units))),
+            rule => rule,
+        }
+    }
+}
+
+/// Recursive descent parser for the rule language described on `Rule`.
+struct RuleParser<'a> {
+    source: &'a str,
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl<'a> RuleParser<'a> {
+    fn new(source: &'a str) -> Result<Self, PolicyError> {
+        let mut tokens = Vec::new();
+        let mut chars = source.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                _ if c.is_whitespace() => {},
+                '(' | ')' | ',' => tokens.push(c.to_string()),
+                '\'' | '[' => {
+                    let close = if c == '[' { ']' } else { '\'' };
+                    let mut tok = c.to_string();
+                    loop {
+                        match chars.next() {
+                            Some(d) => tok.push(d),
+                            None => return Err(PolicyError::Malformed(
+                                format!("{}: missing closing {}", source, close))),
+                        }
+                        if tok.ends_with(close) && tok.len() > 1 {
+                            break;
+                        }
+                    }
+                    tokens.push(tok);
+                },
+                _ => {
+                    let mut tok = c.to_string();
+                    while let Some(d) = chars.peek() {
+                        if d.is_whitespace() || "(),'[".contains(*d) {
+                            break;
+                        }
+                        tok.push(*d);
+                        chars.next();
+                    }
+                    tokens.push(tok);
+                },
+            }
+        }
+        Ok(RuleParser { source, tokens, pos: 0 })
+    }
+
+    fn error(&self, msg: &str) -> PolicyError {
+        PolicyError::Malformed(format!("{}: {}", self.source, msg))
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|tok| tok.as_str())
+    }
+
+    fn next(&mut self) -> Option<String> {
+        let tok = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        tok
+    }
+
+    fn expect_next(&mut self, what: &str) -> Result<String, PolicyError> {
+        self.next().ok_or_else(|| self.error(&format!("expected {}", what)))
+    }
+
+    fn parse_or(&mut self) -> Result<Rule, PolicyError> {
+        let mut rules = vec![self.parse_and()?];
+        while self.peek() == Some("or") {
+            self.next();
+            rules.push(self.parse_and()?);
+        }
+        Ok(if rules.len() == 1 { rules.remove(0) } else { Rule::Or(rules) })
+    }
+
+    fn parse_and(&mut self) -> Result<Rule, PolicyError> {
+        let mut rules = vec![self.parse_unary()?];
+        while self.peek() == Some("and") {
+            self.next();
+            rules.push(self.parse_unary()?);
+        }
+        Ok(if rules.len() == 1 { rules.remove(0) } else { Rule::And(rules) })
+    }
+
+    fn parse_unary(&mut self) -> Result<Rule, PolicyError> {
+        let tok = self.expect_next("a rule")?;
+        match tok.as_str() {
+            "not" => Ok(Rule::Not(Box::new(self.parse_unary()?))),
+            "(" => {
+                let rule = self.parse_or()?;
+                match self.next().as_deref() {
+                    Some(")") => Ok(rule),
+                    _ => Err(self.error("expected ')'")),
+                }
+            },
+            "count" => {
+                let (min, max) = self.parse_range()?;
+                Rule::count(self.parse_class()?, min, max)
+            },
+            "at" => {
+                let pos = self.parse_usize()?;
+                if pos == 0 {
+                    return Err(PolicyError::ZeroPosition);
+                }
+                Ok(Rule::At { pos, class: self.parse_class()? })
+            },
+            "one-of" => {
+                let mut positions = vec![self.parse_usize()?];
+                while self.peek() == Some(",") {
+                    self.next();
+                    positions.push(self.parse_usize()?);
+                }
+                Rule::exactly_one(positions, self.parse_class()?)
+            },
+            "min-length" => Ok(Rule::MinLength(self.parse_usize()?)),
+            "old" | "new" => {
+                let range = self.expect_next("N-M")?;
+                let letter = self.expect_next("a letter")?;
+                let policy_str = format!("{} {}", range, letter);
+                if tok == "old" {
+                    Ok(Rule::Old(OldPolicy::from_str(&policy_str)?))
+                } else {
+                    Ok(Rule::New(NewPolicy::from_str(&policy_str)?))
+                }
+            },
+            _ => Err(self.error(&format!("unknown rule '{}'", tok))),
+        }
+    }
+
+    fn parse_usize(&mut self) -> Result<usize, PolicyError> {
+        let tok = self.expect_next("a number")?;
+        tok.parse::<usize>().map_err(|_e| self.error(&format!("expected a number, found '{}'", tok)))
+    }
+
+    fn parse_range(&mut self) -> Result<(usize, usize), PolicyError> {
+        let tok = self.expect_next("N-M")?;
+        let splits: Vec<&str> = tok.split('-').collect();
+        match splits.as_slice() {
+            [min, max] => match (min.parse::<usize>(), max.parse::<usize>()) {
+                (Ok(min), Ok(max)) => Ok((min, max)),
+                _ => Err(self.error(&format!("expected N-M, found '{}'", tok))),
+            },
+            _ => Err(self.error(&format!("expected N-M, found '{}'", tok))),
+        }
+    }
+
+    fn parse_class(&mut self) -> Result<CharClass, PolicyError> {
+        let tok = self.expect_next("a character class")?;
+        let inner: Vec<char> = tok.chars().skip(1).take(tok.chars().count().saturating_sub(2)).collect();
+        match tok.as_str() {
+            "digit" => Ok(CharClass::Digit),
+            "lower" => Ok(CharClass::Lower),
+            "upper" => Ok(CharClass::Upper),
+            "alpha" => Ok(CharClass::Alpha),
+            "any" => Ok(CharClass::Any),
+            _ if tok.starts_with('\'') && inner.len() == 1 => Ok(CharClass::Letter(inner[0])),
+            _ if tok.starts_with('[') && !inner.is_empty() => Ok(CharClass::Set(inner)),
+            _ => Err(self.error(&format!("expected a character class, found '{}'", tok))),
+        }
+    }
+}
+
+/// Parse a rules file with one `name = rule` per line. Blank lines and lines
+/// starting with `#` are ignored.
+fn parse_rules(input: &str) -> Result<Vec<(String, Rule)>, PolicyError> {
+    let mut rules = Vec::new();
+    for line in input.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let eq = line.find('=').ok_or_else(|| PolicyError::Malformed(line.to_string()))?;
+        let (name, rule_str) = (line[..eq].trim(), &line[eq + 1..]);
+        if name.is_empty() {
+            return Err(PolicyError::Malformed(line.to_string()));
+        }
+        rules.push((name.to_string(), Rule::parse(rule_str)?));
+    }
+    Ok(rules)
+}
+
+/// Run with `--report` to also list every password failing each policy, and
+/// with `--rules FILE` to count the passwords satisfying each rule in FILE.
+/// With `--graphemes`, letters and positions are grapheme clusters of the NFC
+/// normalized passwords rather than code points. With `--strict-positions`,
+/// also count passwords under "new-strict", the new interpretation where
+/// positions past the end of a password are a violation.
+///
+/// Run with `--stats table|json` to also print statistics about the
+/// database, as a table or as JSON.
+///
+/// Run with `--generate N [--seed S]` to instead print a random database of N
+/// lines.
 fn main() {
-    let lines = util::read_lines("inputs/d2").unwrap();
+    let args: Vec<String> = std::env::args().collect();
+    let arg_value = |flag: &str| args.iter().position(|arg| arg == flag).and_then(|i| args.get(i + 1));
+    let report_mode = args.iter().any(|arg| arg == "--report");
+    let graphemes = args.iter().any(|arg| arg == "--graphemes");
+    let strict_positions = args.iter().any(|arg| arg == "--strict-positions");
+    let rules_path = arg_value("--rules");
+    let stats_format = arg_value("--stats");
 
-    // count passwords that satisfy the *old* policy scheme
-    let mut old_count = 0;
-    let mut new_count = 0;
-    for line in lines {
-        let ln = line.unwrap();
-        // panic if lines fails to parse!
-        let (policy, word) = parse_line(&ln, OldPolicy::from_str).unwrap();
-        if policy.check(&word) {
-            // println!("line {} -- password {} passes old check!", ln, word);
-            old_count += 1;
+    if let Some(n) = arg_value("--generate") {
+        let n = n.parse::<usize>().expect("--generate takes a number of lines");
+        let seed = arg_value("--seed").map_or(2020, |s| s.parse::<u64>().expect("--seed takes a number"));
+        let gen = PasswordGenerator::new("abcdefghijklmnopqrstuvwxyz", 1, 20).unwrap();
+        for line in generate_database(&gen, n, &mut Rng::new(seed)) {
+            println!("{}", line);
         }
+        return;
+    }
+    let lines: Vec<String> = util::read_lines("inputs/d2").unwrap()
+        .map(|line| line.unwrap())
+        .collect();
+
+    // count passwords that satisfy each registered policy scheme
+    let mut registry = PolicyRegistry::default();
+    if graphemes {
+        registry.register::<InGraphemes<OldPolicy>>("old");
+        registry.register::<InGraphemes<NewPolicy>>("new");
+    }
+    match (strict_positions, graphemes) {
+        (true, false) => registry.register::<StrictPositions>("new-strict"),
+        (true, true) => registry.register::<InGraphemes<StrictPositions>>("new-strict"),
+        (false, _) => (),
+    }
+    let names = registry.names();
+    // panic if lines fails to parse!
+    let counts = registry.count_valid(&names, lines.iter()).unwrap();
+    for (name, count) in names.iter().zip(counts.iter()) {
+        println!("*** {} count = {}", name.to_uppercase(), count);
+    }
+
+    if let Some(format) = stats_format {
+        let units = if graphemes { TextUnits::Graphemes } else { TextUnits::CodePoints };
+        let stats = Stats::collect(&registry, units, lines.iter()).unwrap();
+        match format.as_str() {
+            "json" => println!("{}", stats.to_json()),
+            "table" => print!("{}", stats.table()),
+            _ => panic!("--stats takes table or json"),
+        }
+    }
 
-        // check the *new* policy
-        let (policy, word) = parse_line(&ln, NewPolicy::from_str).unwrap();
-        if policy.check(&word) {
-            // println!("line {} -- password {} passes new check!", ln, word);
-            new_count += 1;
+    if report_mode {
+        for name in names.iter() {
+            println!("*** {} failures:", name.to_uppercase());
+            for failure in registry.report(name, lines.iter()).unwrap() {
+                println!("{}", failure);
+            }
+        }
+    }
+
+    if let Some(path) = rules_path {
+        let rules = parse_rules(&util::read_to_string(path).unwrap()).unwrap();
+        for (name, rule) in rules.into_iter() {
+            let rule = if graphemes { rule.with_units(TextUnits::Graphemes) } else { rule };
+            let count = lines.iter()
+                .filter(|line| rule.check(split_line(line).unwrap().1))
+                .count();
+            println!("*** rule {} ({}) count = {}", name, rule, count);
         }
     }
-    println!("*** OLD count = {}", old_count);
-    println!("*** NEW count = {}", new_count);
 }
 
 #[cfg(test)]
@@ -177,7 +1303,7 @@ mod test {
 
     #[test]
     fn test_policy() {
-        let policy1 = OldPolicy::new('a', 1, 3);
+        let policy1 = OldPolicy::new('a', 1, 3).unwrap();
         assert!(policy1.check("abc"));
         assert!(policy1.check("abarac"));
         assert!(!policy1.check("abaracadabra"));
@@ -189,11 +1315,11 @@ mod test {
     /// 2-9 c: ccccccccc
     #[test]
     fn test_given_cases() {
-        let policy1 = OldPolicy::new('a', 1, 3);
+        let policy1 = OldPolicy::new('a', 1, 3).unwrap();
         assert!(policy1.check("abcde"));
-        let policy2 = OldPolicy::new('b', 1, 3);
+        let policy2 = OldPolicy::new('b', 1, 3).unwrap();
         assert!(!policy2.check("cdefg"));
-        let policy3 = OldPolicy::new('c', 2, 9);
+        let policy3 = OldPolicy::new('c', 2, 9).unwrap();
         assert!(policy3.check("ccccccccc"));
     }
 
@@ -216,14 +1342,357 @@ mod test {
 
     #[test]
     fn test_parse_line() {
-        assert!(parse_line("1-3 a: foobar", OldPolicy::from_str).is_ok());
-        assert!(parse_line("1- a: foobar", OldPolicy::from_str).is_err());
-        assert!(parse_line("1-3 a: ", OldPolicy::from_str).is_err());
-        let (policy, word) = parse_line("1-3 a: foobar", OldPolicy::from_str).unwrap();
+        assert!(parse_line::<OldPolicy>("1-3 a: foobar").is_ok());
+        assert!(parse_line::<OldPolicy>("1- a: foobar").is_err());
+        assert!(parse_line::<OldPolicy>("1-3 a: ").is_err());
+        let (policy, word) = parse_line::<OldPolicy>("1-3 a: foobar").unwrap();
         assert_eq!(word, "foobar".to_string());
         assert!(policy.check(&word));
 
         // test parsing new policies
-        assert!(parse_line("1-3 a: foobar", NewPolicy::from_str).is_ok());
+        assert!(parse_line::<NewPolicy>("1-3 a: foobar").is_ok());
+    }
+
+    #[test]
+    fn test_explain() {
+        assert_eq!(OldPolicy::new('a', 1, 3).unwrap().explain(), "'a' must occur 1 to 3 times");
+        assert_eq!(
+            NewPolicy::new('c', 2, 9).unwrap().explain(),
+            "exactly one of positions 2 and 9 must be 'c'"
+        );
+    }
+
+    #[test]
+    fn test_registry() {
+        let lines = ["1-3 a: abcde", "1-3 b: cdefg", "2-9 c: ccccccccc"];
+        let registry = PolicyRegistry::default();
+        assert_eq!(registry.names(), vec!["old", "new"]);
+        assert_eq!(registry.count_valid(&["old", "new"], lines.iter()), Ok(vec![2, 1]));
+        assert_eq!(registry.count_valid(&["new", "new"], lines.iter()), Ok(vec![1, 1]));
+        assert_eq!(registry.count_valid(&[], lines.iter()), Ok(vec![]));
+        assert_eq!(
+            registry.count_valid(&["bogus"], lines.iter()),
+            Err(PolicyError::UnknownPolicy("bogus".to_string()))
+        );
+        assert_eq!(
+            registry.count_valid(&["old"], ["1-3 a abcde"].iter()),
+            Err(PolicyError::Malformed("1-3 a abcde".to_string()))
+        );
+
+        let mut registry = PolicyRegistry::new();
+        registry.register::<NewPolicy>("toboggan");
+        registry.register::<OldPolicy>("sled");
+        registry.register::<OldPolicy>("toboggan");
+        assert_eq!(registry.names(), vec!["toboggan", "sled"]);
+        assert_eq!(registry.count_valid(&["toboggan"], lines.iter()), Ok(vec![2]));
+        let (policy, word) = registry.get("sled").unwrap()("1-3 a: abcde").unwrap();
+        assert!(policy.check(&word));
+    }
+
+    #[test]
+    fn test_check_detailed() {
+        assert_eq!(OldPolicy::new('a', 1, 3).unwrap().check_detailed("abcde"), Ok(()));
+        let violation = OldPolicy::new('b', 1, 3).unwrap().check_detailed("cdefg").unwrap_err();
+        assert_eq!(violation, Violation::Count { letter: "b".to_string(), found: 0, min: 1, max: 3 });
+        assert_eq!(violation.to_string(), "found 0 'b', need 1..=3");
+
+        assert_eq!(NewPolicy::new('a', 1, 3).unwrap().check_detailed("abcde"), Ok(()));
+        let violation = NewPolicy::new('b', 1, 3).unwrap().check_detailed("cdefg").unwrap_err();
+        assert_eq!(violation.to_string(), "neither position 1 nor 3 contains 'b'");
+        let violation = NewPolicy::new('c', 2, 9).unwrap().check_detailed("ccccccccc").unwrap_err();
+        assert_eq!(violation, Violation::BothPositions { letter: "c".to_string(), pos1: 2, pos2: 9 });
+        assert_eq!(violation.to_string(), "positions 2 and 9 both contain 'c'");
+    }
+
+    #[test]
+    fn test_report() {
+        let lines = ["1-3 a: abcde", "1-3 b: cdefg", "2-9 c: ccccccccc"];
+        let registry = PolicyRegistry::default();
+        let failures = registry.report("old", lines.iter()).unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].to_string(), "line 2: cdefg: found 0 'b', need 1..=3");
+
+        let failures = registry.report("new", lines.iter()).unwrap();
+        assert_eq!(failures.iter().map(|f| f.line_num).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(failures[1].password, "ccccccccc");
+        assert!(registry.report("bogus", lines.iter()).is_err());
+    }
+
+    #[test]
+    fn test_invalid_policies_rejected() {
+        assert_eq!(OldPolicy::new('a', 3, 1).err(), Some(PolicyError::EmptyRange { min: 3, max: 1 }));
+        assert_eq!(OldPolicy::from_str("3-1 a").err(), Some(PolicyError::EmptyRange { min: 3, max: 1 }));
+        assert!(OldPolicy::from_str("0-0 a").is_ok());
+        assert_eq!(NewPolicy::new('a', 0, 2).err(), Some(PolicyError::ZeroPosition));
+        assert_eq!(NewPolicy::from_str("1-0 a").err(), Some(PolicyError::ZeroPosition));
+        assert_eq!(
+            PolicyRegistry::default().count_valid(&["new"], ["0-3 a: abc"].iter()),
+            Err(PolicyError::ZeroPosition)
+        );
+    }
+
+    #[test]
+    fn test_new_policy_short_password() {
+        let policy = NewPolicy::new('a', 1, 9).unwrap();
+        assert!(policy.check("abc"));
+        assert!(!policy.check("bbc"));
+        assert!(!policy.check(""));
+        let policy = NewPolicy::new('a', 8, 9).unwrap();
+        assert_eq!(
+            policy.check_detailed("abc"),
+            Err(Violation::NeitherPosition { letter: "a".to_string(), pos1: 8, pos2: 9 })
+        );
+
+        let strict = NewPolicy::new('a', 1, 9).unwrap().strict(true);
+        let violation = strict.check_detailed("abc").unwrap_err();
+        assert_eq!(violation, Violation::PositionOutOfRange { pos: 9, len: 3 });
+        assert_eq!(violation.to_string(), "position 9 is past the end of the 3 character password");
+        assert!(strict.check("abcdefghi"));
+
+        let lines = ["1-9 a: abc", "1-3 a: abc", "1-3 a: bca"];
+        let mut registry = PolicyRegistry::default();
+        registry.register::<StrictPositions>("new-strict");
+        assert_eq!(registry.count_valid(&["new", "new-strict"], lines.iter()), Ok(vec![3, 2]));
+        assert_eq!(
+            StrictPositions::parse("1-9 a").unwrap().explain(),
+            "exactly one of positions 1 and 9 must be 'a', within the password"
+        );
+    }
+
+    #[test]
+    fn test_rule_primitives() {
+        let rule = Rule::parse("count 1-3 'a'").unwrap();
+        assert!(matches!(rule, Rule::Old(_)));
+        assert!(rule.check("abcde"));
+        assert!(!rule.check("cdefg"));
+        assert_eq!(rule.check_detailed("cdefg"), OldPolicy::from_str("1-3 a").unwrap().check_detailed("cdefg"));
+
+        let rule = Rule::parse("one-of 2,9 'c'").unwrap();
+        assert!(matches!(rule, Rule::New(_)));
+        assert!(!rule.check("ccccccccc"));
+        assert!(Rule::parse("new 1-3 a").unwrap().check("abcde"));
+        assert!(!Rule::parse("old 1-3 b").unwrap().check("cdefg"));
+    }
+
+    #[test]
+    fn test_rule_atoms() {
+        assert!(Rule::parse("count 2-3 digit").unwrap().check("a1b2"));
+        assert!(!Rule::parse("count 2-3 digit").unwrap().check("a1b"));
+        assert!(Rule::parse("count 1-1 [xyz]").unwrap().check("abcy"));
+        assert!(Rule::parse("at 1 upper").unwrap().check("Abc"));
+        assert!(!Rule::parse("at 4 any").unwrap().check("Abc"));
+        assert!(Rule::parse("one-of 1, 2, 3 digit").unwrap().check("a1b"));
+        assert!(!Rule::parse("one-of 1,2,3 alpha").unwrap().check("a1b"));
+        assert!(Rule::parse("min-length 3").unwrap().check("abc"));
+        assert!(!Rule::parse("min-length 4").unwrap().check("abc"));
+    }
+
+    #[test]
+    fn test_rule_boolean() {
+        let rule = Rule::parse("min-length 8 and count 1-100 digit or not at 1 lower").unwrap();
+        assert!(rule.check("abcdefg1"));
+        assert!(rule.check("Abc"));
+        assert!(!rule.check("abc1"));
+        assert_eq!(rule.to_string(), "(min-length 8 and count 1-100 digit) or not at 1 lower");
+
+        let rule = Rule::parse("not (at 1 'a' or at 2 'a') and min-length 2").unwrap();
+        assert!(rule.check("bcd"));
+        assert!(!rule.check("ba"));
+        assert_eq!(rule.to_string(), "not (at 1 'a' or at 2 'a') and min-length 2");
+        assert_eq!(
+            rule.check_detailed("b").unwrap_err().to_string(),
+            "`min-length 2` is not satisfied"
+        );
+
+        // rendered rules parse back to equivalent rules
+        let reparsed = Rule::parse(&rule.to_string()).unwrap();
+        assert_eq!(reparsed.to_string(), rule.to_string());
+        let rule = Rule::parse("old 1-2 \u{1f44d}\u{1f3fd} or new 1-3 a").unwrap();
+        assert_eq!(rule.to_string(), "old 1-2 \u{1f44d}\u{1f3fd} or new 1-3 a");
+        assert_eq!(Rule::parse(&rule.to_string()).unwrap().to_string(), rule.to_string());
+    }
+
+    #[test]
+    fn test_rule_errors() {
+        assert!(Rule::parse("").is_err());
+        assert!(Rule::parse("count 1-3").is_err());
+        assert!(Rule::parse("count 3 'a'").is_err());
+        assert!(Rule::parse("at 1 'ab'").is_err());
+        assert!(Rule::parse("(min-length 1").is_err());
+        assert!(Rule::parse("min-length 1)").is_err());
+        assert!(Rule::parse("at 1 'a").is_err());
+        assert!(Rule::parse("frobnicate 3").is_err());
+        assert_eq!(Rule::parse("count 3-1 digit").err(), Some(PolicyError::EmptyRange { min: 3, max: 1 }));
+        assert_eq!(Rule::parse("at 0 digit").err(), Some(PolicyError::ZeroPosition));
+        assert_eq!(Rule::parse("one-of 0,1 'a'").err(), Some(PolicyError::ZeroPosition));
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let rules = parse_rules(
+            "# company rules
+             long = min-length 8
+
+             strong = count 1-100 digit and count 1-100 upper
+             puzzle = old 1-3 a or new 1-3 a"
+        ).unwrap();
+        let names: Vec<&str> = rules.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["long", "strong", "puzzle"]);
+        assert!(rules[1].1.check("Passw0rd"));
+        assert!(!rules[1].1.check("password"));
+        assert!(rules[2].1.check("abcde"));
+        assert!(parse_rules("no equals sign").is_err());
+        assert!(parse_rules("= min-length 1").is_err());
+    }
+
+    #[test]
+    fn test_generator_all() {
+        let gen = PasswordGenerator::new("ba", 0, 2).unwrap();
+        let all: Vec<String> = gen.all().collect();
+        assert_eq!(all, vec!["", "a", "b", "aa", "ab", "ba", "bb"]);
+        assert_eq!(PasswordGenerator::new("abc", 3, 3).unwrap().all().count(), 27);
+        assert!(PasswordGenerator::new("", 0, 2).is_none());
+        assert!(PasswordGenerator::new("a", 3, 2).is_none());
+        assert_eq!(gen.count(), Some(7));
+        assert_eq!(PasswordGenerator::new("ab", 0, 64).unwrap().count(), None);
+
+        let policy = OldPolicy::new('a', 1, 1).unwrap();
+        let satisfying: Vec<String> = gen.exhaustive(&policy, true).collect();
+        assert_eq!(satisfying, vec!["a", "ab", "ba"]);
+        assert_eq!(gen.exhaustive(&policy, false).count(), 4);
+    }
+
+    /// Sampled passwords satisfy (or violate) their policy, and sampling from
+    /// generators small enough to search exhaustively only fails when no such
+    /// password exists.
+    #[test]
+    fn test_sample_round_trip() {
+        let mut rng = Rng::new(2020);
+        for _ in 0..300 {
+            let alphabet = ["a", "ab", "abc", "bc"][rng.gen_range(0, 4)];
+            let min_len = rng.gen_range(0, 4);
+            let gen = PasswordGenerator::new(alphabet, min_len, min_len + rng.gen_range(0, 4)).unwrap();
+            let (n1, n2) = (rng.gen_range(1, 8), rng.gen_range(1, 8));
+            let policies: Vec<Box<dyn PasswordPolicy>> = vec![
+                Box::new(OldPolicy::new('a', n1.min(n2) - 1, n1.max(n2) - 1).unwrap()),
+                Box::new(NewPolicy::new('a', n1, n2).unwrap()),
+                Box::new(NewPolicy::new('a', n1, n2).unwrap().strict(true)),
+                Box::new(Rule::parse("count 1-2 'b' and not at 1 'a'").unwrap()),
+            ];
+            for policy in policies.iter() {
+                for satisfying in [true, false].iter() {
+                    match policy.sample(&gen, *satisfying, &mut rng) {
+                        Some(password) => {
+                            assert_eq!(policy.check(&password), *satisfying, "{}: {}", policy.explain(), password);
+                            let len = password.chars().count();
+                            assert!(gen.min_len <= len && len <= gen.max_len);
+                            assert!(password.chars().all(|c| alphabet.contains(c)));
+                        },
+                        None => assert_eq!(gen.exhaustive(policy.as_ref(), *satisfying).next(), None),
+                    }
+                }
+            }
+        }
+    }
+
+
+    #[test]
+    fn test_sample_rare_passwords() {
+        let mut rng = Rng::new(2020);
+        // random draws almost never find the one satisfying password, but the
+        // generator is small enough to search
+        let rule = Rule::parse("count 5-5 [j]").unwrap();
+        let gen = PasswordGenerator::new("abcdefghij", 0, 5).unwrap();
+        assert_eq!(rule.sample(&gen, true, &mut rng), Some("jjjjj".to_string()));
+        // too many passwords to search, so sampling gives up even though
+        // "zzzzzzzzzzzz" satisfies the rule
+        let rule = Rule::parse("count 12-20 [z]").unwrap();
+        let gen = PasswordGenerator::new("abcdefghijklmnopqrstuvwxyz", 1, 20).unwrap();
+        assert_eq!(rule.sample(&gen, true, &mut rng), None);
+        assert!(rule.check(&"z".repeat(12)));
+    }
+    #[test]
+    fn test_generate_database() {
+        let gen = PasswordGenerator::new("abc", 1, 6).unwrap();
+        let lines = generate_database(&gen, 50, &mut Rng::new(7));
+        assert_eq!(lines, generate_database(&gen, 50, &mut Rng::new(7)));
+        let registry = PolicyRegistry::default();
+        let counts = registry.count_valid(&["old"], lines.iter()).unwrap();
+        assert!(0 < counts[0] && counts[0] < 50);
+    }
+
+    #[test]
+    fn test_grapheme_units() {
+        // precomposed U+00E9 vs 'e' followed by a combining acute accent
+        let precomposed = "caf\u{e9}";
+        let combining = "cafe\u{301}";
+        let policy = OldPolicy::new('\u{e9}', 1, 1).unwrap();
+        assert!(policy.check(precomposed));
+        assert!(!policy.check(combining));
+        let policy = policy.with_units(TextUnits::Graphemes);
+        assert!(policy.check(precomposed));
+        assert!(policy.check(combining));
+
+        // the accent is its own position when counting code points
+        let policy = NewPolicy::new('x', 2, 4).unwrap();
+        assert!(!policy.check("e\u{301}xy"));
+        assert!(policy.with_units(TextUnits::Graphemes).check("e\u{301}xy"));
+        assert_eq!(TextUnits::Graphemes.split("e\u{301}xy"), vec!["\u{e9}", "x", "y"]);
+        assert_eq!(TextUnits::CodePoints.split("e\u{301}xy").len(), 4);
+    }
+
+    #[test]
+    fn test_parse_grapheme_letter() {
+        // thumbs up with a skin tone modifier has no precomposed form
+        let policy = OldPolicy::parse("1-2 \u{1f44d}\u{1f3fd}").unwrap();
+        assert!(policy.check("\u{1f44d}\u{1f3fd}\u{1f44d}"));
+        assert!(!policy.check("\u{1f44d}\u{1f3fd}".repeat(3).as_str()));
+        // a decomposed letter is matched as a grapheme cluster, so passwords are
+        // normalized too
+        let policy = NewPolicy::parse("1-3 e\u{301}").unwrap();
+        assert!(policy.check("\u{e9}ab"));
+        assert!(policy.check("e\u{301}ab"));
+        assert!(NewPolicy::parse("1-3 ab").is_err());
+        // a precomposed letter is matched code point by code point
+        let policy = NewPolicy::parse("1-3 \u{e9}").unwrap();
+        assert!(policy.check("\u{e9}ab"));
+        assert!(!policy.check("e\u{301}ab"));
+
+        let lines = ["1-1 \u{e9}: cafe\u{301}"];
+        let mut registry = PolicyRegistry::default();
+        assert_eq!(registry.count_valid(&["old"], lines.iter()).unwrap(), vec![0]);
+        registry.register::<InGraphemes<OldPolicy>>("old");
+        assert_eq!(registry.count_valid(&["old"], lines.iter()).unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let lines = ["1-3 a: abcde", "1-3 b: cdefg", "2-9 c: ccccccccc"];
+        let stats = Stats::collect(&PolicyRegistry::default(), TextUnits::CodePoints, lines.iter()).unwrap();
+        assert_eq!(stats.lines, 3);
+        assert_eq!((stats.old_only, stats.new_only, stats.both, stats.neither), (1, 0, 1, 1));
+        assert_eq!(stats.range_widths.get(&2), Some(&2));
+        assert_eq!(stats.lengths.get(&5), Some(&2));
+        assert_eq!(stats.most_common_failures(), vec![
+            ("new", "letter in both positions", 1),
+            ("new", "letter in neither position", 1),
+            ("old", "too few of the letter", 1),
+        ]);
+        assert!(stats.to_json().starts_with("{\"lines\": 3, \"old_only\": 1,"));
+        assert!(stats.table().contains("letter c"));
+
+        let lines = ["1-1 e\u{301}: e\u{301}x", "1-1 \u{e9}: \u{e9}x"];
+        let stats = Stats::collect(&PolicyRegistry::default(), TextUnits::CodePoints, lines.iter()).unwrap();
+        // the decomposed letter is matched as a grapheme cluster, the precomposed one isn't
+        assert_eq!(stats.letters.get("\u{e9}"), Some(&2));
+        assert_eq!(stats.lengths.get(&2), Some(&2));
+        let mut registry = PolicyRegistry::default();
+        registry.register::<InGraphemes<OldPolicy>>("old");
+        registry.register::<InGraphemes<NewPolicy>>("new");
+        let lines = ["1-1 \u{e9}: e\u{301}x"];
+        let stats = Stats::collect(&registry, TextUnits::Graphemes, lines.iter()).unwrap();
+        assert_eq!(stats.lengths.get(&2), Some(&1));
+        assert_eq!(stats.both, 0);
+        assert_eq!(stats.old_only, 1);
     }
 }
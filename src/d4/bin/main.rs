@@ -68,21 +68,302 @@
 /// Continue to treat cid as optional. In your batch file, how many passports are valid?
 
 extern crate aoc_2020;
+extern crate regex;
 
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::str::FromStr;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use aoc_2020::json;
+use aoc_2020::rng::Rng;
 use aoc_2020::util::read_to_string;
+use regex::Regex;
 
-const REQ_FIELDS: [&'static str; 7] = [
-    "byr",
-    "iyr",
-    "eyr",
-    "hgt",
-    "hcl",
-    "ecl",
-    "pid",
-];
+/// The rules above, in the format read by `Schema::parse`
+const DEFAULT_SCHEMA: &str = "\
+# field  presence  rule
+byr required int 1920-2002
+iyr required int 2010-2020
+eyr required int 2020-2030
+hgt required units cm:150-193 in:59-76
+hcl required regex ^#[0-9a-fA-F]{6}$
+ecl required enum amb blu brn gry grn hzl oth
+pid required regex ^[0-9]{9}$
+cid optional any
+";
+
+/// How the value of a field is checked.
+#[derive(Clone, Debug)]
+pub enum FieldRule {
+    /// `any`: any value
+    Any,
+    /// `int MIN-MAX`: an integer in the inclusive range
+    Int { min: usize, max: usize },
+    /// `regex PATTERN`: a value matching the pattern, which can't contain whitespace
+    Regex(Regex),
+    /// `enum A B ...`: exactly one of the listed values
+    Enum(Vec<String>),
+    /// `units U:MIN-MAX ...`: an integer followed by one of the units, in that
+    /// unit's inclusive range
+    Units(Vec<(String, usize, usize)>),
+}
+
+impl FieldRule {
+    pub fn check(&self, value: &str) -> bool {
+        self.violation(value).is_none()
+    }
+
+    /// Return the reason `value` breaks the rule, if it does
+    pub fn violation(&self, value: &str) -> Option<Reason> {
+        let in_range = |num: &str, min: usize, max: usize, unit: &str| match num.parse::<usize>() {
+            Err(_) => Some(Reason::NotANumber),
+            Ok(v) if v < min => Some(Reason::BelowMin(format!("{}{}", min, unit))),
+            Ok(v) if v > max => Some(Reason::AboveMax(format!("{}{}", max, unit))),
+            Ok(_) => None,
+        };
+        match self {
+            FieldRule::Any => None,
+            FieldRule::Int { min, max } => in_range(value, *min, *max, ""),
+            FieldRule::Regex(re) if re.is_match(value) => None,
+            FieldRule::Regex(re) => Some(Reason::NoMatch(re.as_str().to_string())),
+            FieldRule::Enum(values) if values.iter().any(|v| v == value) => None,
+            FieldRule::Enum(values) => Some(Reason::NotOneOf(values.clone())),
+            FieldRule::Units(units) => match units.iter().find(|(unit, _, _)| value.ends_with(unit.as_str())) {
+                Some((unit, min, max)) => in_range(&value[..value.len() - unit.len()], *min, *max, unit),
+                None => Some(Reason::UnknownUnit(units.iter().map(|(unit, _, _)| unit.clone()).collect())),
+            },
+        }
+    }
+}
+
+/// Why a field value breaks its `FieldRule`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reason {
+    NotANumber,
+    /// Smaller than the given minimum, including any unit
+    BelowMin(String),
+    /// Larger than the given maximum, including any unit
+    AboveMax(String),
+    /// Doesn't end with any of the given units
+    UnknownUnit(Vec<String>),
+    /// Doesn't match the given pattern
+    NoMatch(String),
+    NotOneOf(Vec<String>),
+}
+
+impl Reason {
+    /// Describe the kind of reason, without the details of the rule
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Reason::NotANumber => "not a number",
+            Reason::BelowMin(_) => "too small",
+            Reason::AboveMax(_) => "too large",
+            Reason::UnknownUnit(_) => "unknown unit",
+            Reason::NoMatch(_) => "wrong format",
+            Reason::NotOneOf(_) => "unknown value",
+        }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reason::NotANumber => write!(f, "is not a number"),
+            Reason::BelowMin(min) => write!(f, "is below {}", min),
+            Reason::AboveMax(max) => write!(f, "exceeds {}", max),
+            Reason::UnknownUnit(units) => write!(f, "has no unit, expected one of {}", units.join(", ")),
+            Reason::NoMatch(pattern) => write!(f, "doesn't match {}", pattern),
+            Reason::NotOneOf(values) => write!(f, "is not one of {}", values.join(", ")),
+        }
+    }
+}
+
+/// A problem with one field of a passport.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FieldError {
+    Missing(String),
+    Invalid { field: String, value: String, reason: Reason },
+}
+
+impl FieldError {
+    pub fn field(&self) -> &str {
+        match self {
+            FieldError::Missing(field) => field,
+            FieldError::Invalid { field, .. } => field,
+        }
+    }
+
+    /// Describe the kind of error, without the details of the field value
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FieldError::Missing(_) => "missing",
+            FieldError::Invalid { reason, .. } => reason.kind(),
+        }
+    }
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FieldError::Missing(field) => write!(f, "{}: missing", field),
+            FieldError::Invalid { field, value, reason } => write!(f, "{}: {} {}", field, value, reason),
+        }
+    }
+}
 
-const EYE_COLORS: [&'static str; 7] = ["amb", "blu", "brn", "gry", "grn", "hzl", "oth"];
+/// Number of errors by field and kind.
+#[derive(Debug, Default)]
+pub struct FailureCounts(BTreeMap<(String, &'static str), usize>);
+
+impl FailureCounts {
+    pub fn add(&mut self, error: &FieldError) {
+        *self.0.entry((error.field().to_string(), error.kind())).or_insert(0) += 1;
+    }
+
+    /// Return the counts, most common first
+    pub fn most_common(&self) -> Vec<(String, &'static str, usize)> {
+        let mut summary: Vec<_> = self.0.iter()
+            .map(|((field, kind), count)| (field.clone(), *kind, *count))
+            .collect();
+        summary.sort_by_key(|(_, _, count)| Reverse(*count));
+        summary
+    }
+}
+
+/// Count the errors across a batch by field and kind, most common first.
+fn failure_summary<'e, I>(errors: I) -> Vec<(String, &'static str, usize)>
+    where I: IntoIterator<Item = &'e FieldError> {
+    let mut counts = FailureCounts::default();
+    errors.into_iter().for_each(|error| counts.add(error));
+    counts.most_common()
+}
+
+#[derive(Clone, Debug)]
+pub struct FieldSpec {
+    pub name: String,
+    pub required: bool,
+    pub rule: FieldRule,
+}
+
+#[derive(Debug)]
+pub enum SchemaError {
+    /// A line that isn't `FIELD required|optional RULE`, with its 1-based line number
+    Malformed(usize, String),
+    /// An invalid `regex` pattern, with its 1-based line number
+    BadPattern(usize, String),
+    DuplicateField(String),
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaError::Malformed(line_num, line) => write!(f, "line {}: malformed rule `{}`", line_num, line),
+            SchemaError::BadPattern(line_num, err) => write!(f, "line {}: bad pattern: {}", line_num, err),
+            SchemaError::DuplicateField(name) => write!(f, "field {} is described twice", name),
+        }
+    }
+}
+
+/// The fields a passport may have and the rules their values must follow.
+///
+/// A schema is read from lines of the form `FIELD required|optional RULE`, where
+/// RULE is one of the forms described on `FieldRule`. Blank lines and lines
+/// starting with `#` are ignored.
+#[derive(Clone, Debug)]
+pub struct Schema {
+    fields: Vec<FieldSpec>,
+}
+
+impl Schema {
+    pub fn parse(text: &str) -> Result<Self, SchemaError> {
+        let mut fields: Vec<FieldSpec> = Vec::new();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let malformed = || SchemaError::Malformed(idx + 1, line.to_string());
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.len() < 3 {
+                return Err(malformed());
+            }
+            let required = match tokens[1] {
+                "required" => true,
+                "optional" => false,
+                _ => return Err(malformed()),
+            };
+            let args = &tokens[3..];
+            let rule = match (tokens[2], args) {
+                ("any", []) => FieldRule::Any,
+                ("int", [range]) => {
+                    let (min, max) = parse_range(range).ok_or_else(malformed)?;
+                    FieldRule::Int { min, max }
+                },
+                ("regex", [pattern]) => FieldRule::Regex(
+                    Regex::new(pattern).map_err(|e| SchemaError::BadPattern(idx + 1, e.to_string()))?),
+                ("enum", values) if !values.is_empty() =>
+                    FieldRule::Enum(values.iter().map(|v| v.to_string()).collect()),
+                ("units", units) if !units.is_empty() => FieldRule::Units(units.iter()
+                    .map(|unit| {
+                        let (unit, range) = unit.split_at(unit.find(':')?);
+                        let (min, max) = parse_range(&range[1..])?;
+                        Some((unit.to_string(), min, max))
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(malformed)?),
+                _ => return Err(malformed()),
+            };
+            if fields.iter().any(|spec| spec.name == tokens[0]) {
+                return Err(SchemaError::DuplicateField(tokens[0].to_string()));
+            }
+            fields.push(FieldSpec { name: tokens[0].to_string(), required, rule });
+        }
+        Ok(Schema { fields })
+    }
+
+    pub fn field(&self, name: &str) -> Option<&FieldSpec> {
+        self.fields.iter().find(|spec| spec.name == name)
+    }
+
+    pub fn required(&self) -> impl Iterator<Item = &str> {
+        self.fields.iter().filter(|spec| spec.required).map(|spec| spec.name.as_str())
+    }
+
+    /// Make the named field required or optional, adding it with rule `any` if
+    /// the schema doesn't describe it.
+    pub fn set_required(&mut self, name: &str, required: bool) {
+        match self.fields.iter_mut().find(|spec| spec.name == name) {
+            Some(spec) => spec.required = required,
+            None => self.fields.push(FieldSpec { name: name.to_string(), required, rule: FieldRule::Any }),
+        }
+    }
+}
+
+/// How much of a passport to check against a `Schema`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Only check that required fields are present (part one)
+    Presence,
+    /// Also check field values against their rules (part two)
+    Strict,
+}
+
+impl Default for Schema {
+    fn default() -> Self {
+        Schema::parse(DEFAULT_SCHEMA).unwrap()
+    }
+}
+
+/// Parse an inclusive range `MIN-MAX`
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let (min, max) = range.split_at(range.find('-')?);
+    let (min, max) = (min.parse().ok()?, max[1..].parse().ok()?);
+    if min <= max { Some((min, max)) } else { None }
+}
 
 #[derive(Debug)]
 struct Passport<'a>(HashMap<&'a str, &'a str>);
@@ -92,121 +373,1039 @@ impl<'a> Passport<'a> {
         Passport(fields)
     }
 
-    /// Return true iff. all required fields are present:
-    ///
-    /// byr (Birth Year) - four digits; at least 1920 and at most 2002.
-    /// iyr (Issue Year) - four digits; at least 2010 and at most 2020.
-    /// eyr (Expiration Year) - four digits; at least 2020 and at most 2030.
-    /// hgt (Height) - a number followed by either cm or in:
-    /// If cm, the number must be at least 150 and at most 193.
-    /// If in, the number must be at least 59 and at most 76.
-    /// hcl (Hair Color) - a # followed by exactly six characters 0-9 or a-f.
-    /// ecl (Eye Color) - exactly one of: amb blu brn gry grn hzl oth.
-    /// pid (Passport ID) - a nine-digit number, including leading zeroes.
-    /// cid (Country ID) - ignored, missing or not.
-    fn validate(&self) -> bool {
-        if !REQ_FIELDS.iter().all(|req| self.0.contains_key(req)) {
-            return false;
-        }
-        for (key, value) in self.0.iter() {
-            let valid_value = match *key {
-                "byr" => value.parse::<usize>()
-                    .and_then(|v| Ok(v >= 1920 && v <= 2002)).unwrap_or(false),
-                "iyr" => value.parse::<usize>()
-                    .and_then(|v| Ok(v >= 2010 && v <= 2020)).unwrap_or(false),
-                "eyr" => value.parse::<usize>()
-                    .and_then(|v| Ok(v >= 2020 && v <= 2030)).unwrap_or(false),
-                "hgt" => {
-                    // ad-hoc parse height
-                    let unit_pos = value.find(char::is_alphabetic);
-                    if unit_pos.is_none() { return false; }
-                    let unit_pos = unit_pos.unwrap();
-                    let (num_str, unit_str) = value.split_at(unit_pos);
-                    let num = num_str.parse::<usize>();
-                    if num.is_err() { return false; }
-                    let num = num.unwrap();
-                    match unit_str {
-                        "cm" => num >= 150 && num <= 193,
-                        "in" => num >= 59 && num <= 76,
-                        _ => false,
-                    }
-                },
-                "hcl" => {
-                    let chars: Vec<char> = value.chars().collect();
-                    chars.len() == 7 && chars[0] == '#' && chars[1..].iter().all(char::is_ascii_hexdigit)
+    /// Return true iff. all fields required by `schema` are present and, in
+    /// `Mode::Strict`, every field described by `schema` has a valid value.
+    /// Other fields are ignored.
+    fn validate(&self, schema: &Schema, mode: Mode) -> bool {
+        self.validate_detailed(schema, mode).is_ok()
+    }
+
+    /// Like `validate`, but return every missing and invalid field, in schema order.
+    fn validate_detailed(&self, schema: &Schema, mode: Mode) -> Result<(), Vec<FieldError>> {
+        let mut errors: Vec<FieldError> = schema.required()
+            .filter(|req| !self.0.contains_key(req))
+            .map(|req| FieldError::Missing(req.to_string()))
+            .collect();
+        let checked = if mode == Mode::Strict { schema.fields.as_slice() } else { &[] };
+        for spec in checked.iter() {
+            if let Some(value) = self.0.get(spec.name.as_str()) {
+                if let Some(reason) = spec.rule.violation(value) {
+                    errors.push(FieldError::Invalid { field: spec.name.clone(), value: value.to_string(), reason });
+                }
+            }
+        }
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    /// Return the fields in `schema` order, then any others by key.
+    fn ordered_fields(&self, schema: &Schema) -> Vec<(&'a str, &'a str)> {
+        let mut fields: Vec<(&str, &str)> = self.0.iter().map(|(key, value)| (*key, *value)).collect();
+        let schema_pos = |key: &str| schema.fields.iter().position(|spec| spec.name == key).unwrap_or(usize::MAX);
+        fields.sort_by_key(|(key, _)| (schema_pos(key), *key));
+        fields
+    }
+
+    /// Parse the value of field `name` with `parse`, recording in `errors` if the
+    /// field is missing or doesn't parse.
+    fn parse_field<T, F>(&self, name: &str, parse: F, errors: &mut Vec<FieldError>) -> Option<T>
+        where F: Fn(&str) -> Result<T, Reason> {
+        let value = match self.0.get(name) {
+            Some(value) => value,
+            None => {
+                errors.push(FieldError::Missing(name.to_string()));
+                return None;
+            },
+        };
+        parse(value)
+            .map_err(|reason| errors.push(FieldError::Invalid { field: name.to_string(), value: value.to_string(), reason }))
+            .ok()
+    }
+}
+
+/// Height with its unit, e.g. `183cm`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Height {
+    Cm(u32),
+    In(u32),
+}
+
+impl FromStr for Height {
+    type Err = Reason;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+        let num = num.parse::<u32>().map_err(|_e| Reason::NotANumber);
+        match unit {
+            "cm" => Ok(Height::Cm(num?)),
+            "in" => Ok(Height::In(num?)),
+            _ => Err(Reason::UnknownUnit(vec!["cm".to_string(), "in".to_string()])),
+        }
+    }
+}
+
+impl fmt::Display for Height {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Height::Cm(n) => write!(f, "{}cm", n),
+            Height::In(n) => write!(f, "{}in", n),
+        }
+    }
+}
+
+/// Hair color, e.g. `#623a2f`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+impl FromStr for Rgb {
+    type Err = Reason;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad_format = || Reason::NoMatch("^#[0-9a-fA-F]{6}$".to_string());
+        let hex = s.strip_prefix('#').filter(|hex| hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+            .ok_or_else(bad_format)?;
+        let byte = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_e| bad_format());
+        Ok(Rgb(byte(0)?, byte(2)?, byte(4)?))
+    }
+}
+
+impl fmt::Display for Rgb {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.0, self.1, self.2)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EyeColor {
+    Amb,
+    Blu,
+    Brn,
+    Gry,
+    Grn,
+    Hzl,
+    Oth,
+}
+
+const EYE_COLORS: [(&str, EyeColor); 7] = [
+    ("amb", EyeColor::Amb),
+    ("blu", EyeColor::Blu),
+    ("brn", EyeColor::Brn),
+    ("gry", EyeColor::Gry),
+    ("grn", EyeColor::Grn),
+    ("hzl", EyeColor::Hzl),
+    ("oth", EyeColor::Oth),
+];
+
+impl FromStr for EyeColor {
+    type Err = Reason;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EYE_COLORS.iter().find(|(name, _)| *name == s).map(|(_, color)| *color)
+            .ok_or_else(|| Reason::NotOneOf(EYE_COLORS.iter().map(|(name, _)| name.to_string()).collect()))
+    }
+}
+
+impl fmt::Display for EyeColor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, _) = EYE_COLORS.iter().find(|(_, color)| color == self).unwrap();
+        write!(f, "{}", name)
+    }
+}
+
+/// Nine digit passport id, including leading zeroes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PassportId(pub [u8; 9]);
+
+impl FromStr for PassportId {
+    type Err = Reason;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut digits = [0; 9];
+        if s.len() != digits.len() || !s.chars().all(|c| c.is_ascii_digit()) {
+            return Err(Reason::NoMatch("^[0-9]{9}$".to_string()));
+        }
+        for (digit, b) in digits.iter_mut().zip(s.bytes()) {
+            *digit = b - b'0';
+        }
+        Ok(PassportId(digits))
+    }
+}
+
+impl fmt::Display for PassportId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.iter().try_for_each(|digit| write!(f, "{}", digit))
+    }
+}
+
+/// A passport with every part two field present and parsed into its type. The
+/// values aren't checked against any `Schema`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypedPassport {
+    pub byr: u32,
+    pub iyr: u32,
+    pub eyr: u32,
+    pub hgt: Height,
+    pub hcl: Rgb,
+    pub ecl: EyeColor,
+    pub pid: PassportId,
+    pub cid: Option<String>,
+    /// Any other fields, by key
+    pub extras: BTreeMap<String, String>,
+}
+
+impl TypedPassport {
+    /// Parse the fields of `passport`, returning every field that is missing or
+    /// can't be parsed.
+    fn parse(passport: &Passport) -> Result<Self, Vec<FieldError>> {
+        let mut errors = Vec::new();
+        let year = |v: &str| v.parse::<u32>().map_err(|_e| Reason::NotANumber);
+        let parsed = (
+            passport.parse_field("byr", year, &mut errors),
+            passport.parse_field("iyr", year, &mut errors),
+            passport.parse_field("eyr", year, &mut errors),
+            passport.parse_field("hgt", str::parse, &mut errors),
+            passport.parse_field("hcl", str::parse, &mut errors),
+            passport.parse_field("ecl", str::parse, &mut errors),
+            passport.parse_field("pid", str::parse, &mut errors),
+        );
+        match parsed {
+            (Some(byr), Some(iyr), Some(eyr), Some(hgt), Some(hcl), Some(ecl), Some(pid)) => Ok(TypedPassport {
+                byr, iyr, eyr, hgt, hcl, ecl, pid,
+                cid: passport.0.get("cid").map(|v| v.to_string()),
+                extras: passport.0.iter()
+                    .filter(|(key, _)| !TYPED_FIELDS.contains(key))
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+            }),
+            _ => Err(errors),
+        }
+    }
+}
+
+/// Fields with a place in `TypedPassport`
+const TYPED_FIELDS: [&str; 8] = ["byr", "iyr", "eyr", "hgt", "hcl", "ecl", "pid", "cid"];
+
+/// Formats the passport as a single line of the batch format.
+impl fmt::Display for TypedPassport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "byr:{} iyr:{} eyr:{} hgt:{} hcl:{} ecl:{} pid:{}",
+               self.byr, self.iyr, self.eyr, self.hgt, self.hcl, self.ecl, self.pid)?;
+        if let Some(cid) = &self.cid {
+            write!(f, " cid:{}", cid)?;
+        }
+        self.extras.iter().try_for_each(|(key, value)| write!(f, " {}:{}", key, value))
+    }
+}
+
+/// A change to a field value that makes it valid.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Repair {
+    pub field: String,
+    pub from: String,
+    pub to: String,
+    /// Only changes the value's spelling, not what it means, so can be applied
+    /// without review
+    pub safe: bool,
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.field, self.from, self.to)
+    }
+}
+
+impl FieldRule {
+    /// Return changes to `value` that satisfy the rule, safe ones first.
+    fn repairs(&self, value: &str) -> Vec<(String, bool)> {
+        let mut candidates = vec![(value.to_lowercase(), true), (format!("#{}", value), true)];
+        if let FieldRule::Units(units) = self {
+            candidates.extend(units.iter().map(|(unit, _, _)| (format!("{}{}", value, unit), false)));
+        }
+        // a lost or extra leading zero
+        candidates.push((format!("0{}", value), false));
+        if let Some(rest) = value.strip_prefix('0') {
+            candidates.push((rest.to_string(), false));
+        }
+        let mut repairs: Vec<(String, bool)> = Vec::new();
+        for (candidate, safe) in candidates {
+            if candidate != value && self.check(&candidate) && !repairs.iter().any(|(r, _)| *r == candidate) {
+                repairs.push((candidate, safe));
+            }
+        }
+        repairs
+    }
+}
+
+/// A passport with safe repairs applied, and unsafe ones suggested.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Normalized {
+    /// Fields in schema order, then any others by key
+    pub fields: Fields,
+    pub applied: Vec<Repair>,
+    pub suggested: Vec<Repair>,
+}
+
+impl Normalized {
+    /// Repair the invalid fields of `passport` according to `schema`. A field is
+    /// repaired if its first candidate repair is safe; otherwise every candidate
+    /// is suggested and the value is left alone.
+    fn new(passport: &Passport, schema: &Schema) -> Self {
+        let mut normalized = Normalized { fields: Fields(Vec::new()), applied: Vec::new(), suggested: Vec::new() };
+        for (key, value) in passport.ordered_fields(schema) {
+            let repairs = match schema.field(key) {
+                Some(spec) if !spec.rule.check(value) => spec.rule.repairs(value),
+                _ => Vec::new(),
+            };
+            let repair = |(to, safe): &(String, bool)| {
+                Repair { field: key.to_string(), from: value.to_string(), to: to.clone(), safe: *safe }
+            };
+            match repairs.first() {
+                Some(first) if first.1 => {
+                    normalized.fields.0.push((key.to_string(), first.0.clone()));
+                    normalized.applied.push(repair(first));
                 },
-                "ecl" => {
-                    EYE_COLORS.iter().any(|color| color == value)
+                _ => {
+                    normalized.fields.0.push((key.to_string(), value.to_string()));
+                    normalized.suggested.extend(repairs.iter().map(repair));
                 },
-                "pid" => {
-                    let chars: Vec<char> = value.chars().collect();
-                    chars.len() == 9 && chars.iter().all(|c| c.is_digit(10))
+            }
+        }
+        normalized
+    }
+}
+
+/// Formats the repaired fields, see `Fields`.
+impl fmt::Display for Normalized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fields.fmt(f)
+    }
+}
+
+/// Passport fields read from another format, in the order they were read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fields(Vec<(String, String)>);
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ImportError {
+    /// 1-based line number the bad record starts on
+    pub line_num: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line_num, self.reason)
+    }
+}
+
+impl Fields {
+    /// Check that the fields can be written in the batch format
+    fn new(fields: Vec<(String, String)>, line_num: usize) -> Result<Self, ImportError> {
+        let error = |reason: String| Err(ImportError { line_num, reason });
+        for (idx, (key, value)) in fields.iter().enumerate() {
+            let bad = |s: &str| s.is_empty() || s.contains(|c: char| c == ':' || c.is_whitespace());
+            if bad(key) || bad(value) {
+                return error(format!("`{}:{}` can't be written as a batch token", key, value));
+            }
+            if fields[..idx].iter().any(|(k, _)| k == key) {
+                return error(format!("duplicate key {}", key));
+            }
+        }
+        Ok(Fields(fields))
+    }
+}
+
+/// Formats the fields as `key:value` tokens on one line, in order.
+impl fmt::Display for Fields {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tokens: Vec<String> = self.0.iter().map(|(key, value)| format!("{}:{}", key, value)).collect();
+        write!(f, "{}", tokens.join(" "))
+    }
+}
+
+/// Write passports as JSON Lines, one object of string values per passport.
+fn to_json_lines(passports: &[&Passport], schema: &Schema) -> String {
+    passports.iter()
+        .map(|p| {
+            let members: Vec<String> = p.ordered_fields(schema).iter()
+                .map(|(key, value)| format!("{}: {}", json::quote(key), json::quote(value)))
+                .collect();
+            format!("{{{}}}\n", members.join(", "))
+        })
+        .collect()
+}
+
+/// Read passports from JSON Lines, skipping blank lines.
+fn from_json_lines(text: &str) -> Result<Vec<Fields>, ImportError> {
+    text.lines().enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| {
+            let fields = json::parse_flat_object(line).map_err(|reason| ImportError { line_num: idx + 1, reason })?;
+            Fields::new(fields, idx + 1)
+        })
+        .collect()
+}
+
+/// Name of the CSV column holding fields the schema doesn't describe, as batch
+/// format tokens
+const EXTRAS_COLUMN: &str = "extras";
+
+/// Write passports as CSV with a column per schema field, plus `EXTRAS_COLUMN`.
+fn to_csv(passports: &[&Passport], schema: &Schema) -> String {
+    let mut header: Vec<&str> = schema.fields.iter().map(|spec| spec.name.as_str()).collect();
+    header.push(EXTRAS_COLUMN);
+    let mut out = format!("{}\n", header.iter().map(|name| csv_quote(name)).collect::<Vec<String>>().join(","));
+    for p in passports.iter() {
+        let mut row: Vec<String> = schema.fields.iter()
+            .map(|spec| csv_quote(p.0.get(spec.name.as_str()).unwrap_or(&"")))
+            .collect();
+        let extras: Vec<String> = p.ordered_fields(schema).iter()
+            .filter(|(key, _)| schema.field(key).is_none())
+            .map(|(key, value)| format!("{}:{}", key, value))
+            .collect();
+        row.push(csv_quote(&extras.join(" ")));
+        out.push_str(&format!("{}\n", row.join(",")));
+    }
+    out
+}
+
+/// Read passports from CSV with a header row naming the fields. Empty cells are
+/// missing fields, and `EXTRAS_COLUMN` holds any other fields as batch format
+/// tokens.
+fn from_csv(text: &str) -> Result<Vec<Fields>, ImportError> {
+    let mut rows = parse_csv(text)?.into_iter();
+    let header = match rows.next() {
+        Some((_, header)) => header,
+        None => return Ok(Vec::new()),
+    };
+    rows.map(|(line_num, row)| {
+        if row.len() != header.len() {
+            let reason = format!("{} cells, expected {}", row.len(), header.len());
+            return Err(ImportError { line_num, reason });
+        }
+        let mut fields = Vec::new();
+        for (name, cell) in header.iter().zip(row) {
+            if name == EXTRAS_COLUMN {
+                for tok in cell.split_whitespace() {
+                    let (key, value) = parse_token(tok).ok_or_else(|| {
+                        ImportError { line_num, reason: format!("malformed token `{}`", tok) }
+                    })?;
+                    fields.push((key.to_string(), value.to_string()));
                 }
-                _ => true,  // if field is unknown, we ignore it
-            };
-            if !valid_value {
-                return false;
+            } else if !cell.is_empty() {
+                fields.push((name.clone(), cell));
             }
         }
-        true
+        Fields::new(fields, line_num)
+    }).collect()
+}
+
+/// Quote a CSV cell if it contains a comma, quote or line break.
+fn csv_quote(cell: &str) -> String {
+    if cell.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", cell.replace('"', "\"\""))
+    } else {
+        cell.to_string()
     }
 }
 
-pub fn main() {
-    let input = read_to_string("inputs/d4").expect("failed to read inputs/d4");
-    let parse_result = parse_passports(&input).expect("failed to parse input");
-    let mut count = 0;
-    for passwd in parse_result.iter() {
-        if passwd.validate() {
-            count += 1;
+/// Split CSV text into rows of cells, each with the 1-based line number it starts
+/// on. Quoted cells may contain commas, doubled quotes and line breaks. Blank
+/// lines are skipped.
+fn parse_csv(text: &str) -> Result<Vec<(usize, Vec<String>)>, ImportError> {
+    let mut rows = Vec::new();
+    let mut row: Vec<String> = Vec::new();
+    let mut cell = String::new();
+    let (mut line_num, mut row_start) = (1, 1);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if cell.is_empty() => loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        cell.push('"');
+                    },
+                    Some('"') => break,
+                    Some(c) => {
+                        if c == '\n' {
+                            line_num += 1;
+                        }
+                        cell.push(c);
+                    },
+                    None => {
+                        let reason = "unterminated quoted cell".to_string();
+                        return Err(ImportError { line_num: row_start, reason });
+                    },
+                }
+            },
+            ',' => row.push(std::mem::take(&mut cell)),
+            '\r' => {},
+            '\n' => {
+                row.push(std::mem::take(&mut cell));
+                if row.len() > 1 || !row[0].is_empty() {
+                    rows.push((row_start, std::mem::take(&mut row)));
+                }
+                row.clear();
+                line_num += 1;
+                row_start = line_num;
+            },
+            c => cell.push(c),
+        }
+    }
+    if !row.is_empty() || !cell.is_empty() {
+        row.push(cell);
+        rows.push((row_start, row));
+    }
+    Ok(rows)
+}
+
+/// What a generated passport is made to be.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Valid,
+    /// Missing one required field
+    MissingField,
+    /// One field with an `int` or `units` rule is out of range
+    OutOfRange,
+}
+
+impl Kind {
+    /// Whether a passport of this kind passes validation in `mode`
+    pub fn is_valid(self, mode: Mode) -> bool {
+        match self {
+            Kind::Valid => true,
+            Kind::MissingField => false,
+            Kind::OutOfRange => mode == Mode::Presence,
+        }
+    }
+}
+
+/// Relative weights of each `Kind` in a generated batch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mix {
+    pub valid: usize,
+    pub missing_field: usize,
+    pub out_of_range: usize,
+}
+
+impl Mix {
+    fn choose(&self, rng: &mut Rng) -> Kind {
+        let roll = rng.gen_range(0, self.valid + self.missing_field + self.out_of_range);
+        if roll < self.valid {
+            Kind::Valid
+        } else if roll < self.valid + self.missing_field {
+            Kind::MissingField
         } else {
-            println!("Invalid passport: {:?}", passwd)
+            Kind::OutOfRange
+        }
+    }
+}
+
+/// The `regex` pattern of hcl in the default schema
+const RGB_PATTERN: &str = "^#[0-9a-fA-F]{6}$";
+
+/// The `regex` pattern of pid in the default schema
+const PASSPORT_ID_PATTERN: &str = "^[0-9]{9}$";
+
+impl FieldSpec {
+    /// Return a random valid value for the field. Fields with a `regex` rule can
+    /// only be generated if the pattern is one of the built-in `RGB_PATTERN` and
+    /// `PASSPORT_ID_PATTERN`.
+    fn sample(&self, rng: &mut Rng) -> Option<String> {
+        let value = match &self.rule {
+            FieldRule::Int { min, max } => rng.gen_range(*min, max + 1).to_string(),
+            FieldRule::Units(units) => {
+                let (unit, min, max) = rng.choose(units)?;
+                format!("{}{}", rng.gen_range(*min, max + 1), unit)
+            },
+            FieldRule::Enum(values) => rng.choose(values)?.clone(),
+            FieldRule::Regex(re) if re.as_str() == RGB_PATTERN => {
+                let mut byte = || rng.gen_range(0, 256) as u8;
+                Rgb(byte(), byte(), byte()).to_string()
+            },
+            FieldRule::Regex(re) if re.as_str() == PASSPORT_ID_PATTERN => {
+                let mut digits = [0; 9];
+                digits.iter_mut().for_each(|digit| *digit = rng.gen_range(0, 10) as u8);
+                PassportId(digits).to_string()
+            },
+            FieldRule::Any => rng.gen_range(1, 1000).to_string(),
+            FieldRule::Regex(_) => return None,
+        };
+        Some(value).filter(|value| self.rule.check(value))
+    }
+
+    /// Return a random value of the right form but out of range, if the field's
+    /// rule has a range.
+    fn sample_out_of_range(&self, rng: &mut Rng) -> Option<String> {
+        let mut outside = |min: usize, max: usize| {
+            if min > 0 && rng.gen_bool(0.5) {
+                rng.gen_range(min.saturating_sub(100), min)
+            } else {
+                rng.gen_range(max + 1, max + 101)
+            }
+        };
+        match &self.rule {
+            FieldRule::Int { min, max } => Some(outside(*min, *max).to_string()),
+            FieldRule::Units(units) => {
+                let (unit, min, max) = units[0].clone();
+                Some(format!("{}{}", outside(min, max), unit))
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Generate a passport of the given kind, with optional fields present half of
+/// the time, or None if `schema` doesn't allow it.
+fn generate_passport(schema: &Schema, kind: Kind, rng: &mut Rng) -> Option<Fields> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    let mut specs: Vec<&FieldSpec> = Vec::new();
+    for spec in schema.fields.iter() {
+        if !spec.required && !rng.gen_bool(0.5) {
+            continue;
+        }
+        match spec.sample(rng) {
+            Some(value) => {
+                fields.push((spec.name.clone(), value));
+                specs.push(spec);
+            },
+            None if spec.required => return None,
+            None => {},
+        }
+    }
+    match kind {
+        Kind::Valid => {},
+        Kind::MissingField => {
+            let required: Vec<usize> = (0..specs.len()).filter(|idx| specs[*idx].required).collect();
+            fields.remove(*rng.choose(&required)?);
+        },
+        Kind::OutOfRange => {
+            let ranged: Vec<usize> = (0..specs.len())
+                .filter(|idx| matches!(specs[*idx].rule, FieldRule::Int { .. } | FieldRule::Units(_)))
+                .collect();
+            let idx = *rng.choose(&ranged)?;
+            fields[idx].1 = specs[idx].sample_out_of_range(rng)?;
+        },
+    }
+    rng.shuffle(&mut fields);
+    Some(Fields(fields))
+}
+
+/// Generate `n` passports following `schema`, with kinds drawn from `mix`, along
+/// with the kind of each.
+fn generate_batch(schema: &Schema, n: usize, mix: &Mix, rng: &mut Rng) -> Result<Vec<(Kind, Fields)>, String> {
+    (0..n)
+        .map(|_| {
+            let kind = mix.choose(rng);
+            let passport = generate_passport(schema, kind, rng)
+                .ok_or_else(|| format!("can't generate {:?} passports for this schema", kind))?;
+            Ok((kind, passport))
+        })
+        .collect()
+}
+
+/// Write passports in the batch format, breaking lines between tokens at random.
+fn to_batch_fuzzed(passports: &[Fields], rng: &mut Rng) -> String {
+    let mut out = String::new();
+    for (idx, passport) in passports.iter().enumerate() {
+        if idx > 0 {
+            out.push('\n');
+        }
+        for (tok_idx, (key, value)) in passport.0.iter().enumerate() {
+            if tok_idx > 0 {
+                out.push(if rng.gen_bool(0.3) { '\n' } else { ' ' });
+            }
+            out.push_str(&format!("{}:{}", key, value));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Write passports in the batch format, separated by blank lines.
+fn to_batch<P: fmt::Display>(passports: &[P]) -> String {
+    passports.iter().map(|p| format!("{}\n", p)).collect::<Vec<String>>().join("\n")
+}
+
+/// Usage: d4 [--schema FILE] [--optional F,..] [--required F,..] [--mode presence|strict]
+///           [--emit FILE] [--clean FILE] [--strict-parse]
+///           [--input FILE] [--input-format batch|jsonl|csv] [--export-jsonl FILE] [--export-csv FILE]
+///           [--stream [--threads N]] [--generate N [--seed S] [--mix V,M,O]]
+///
+/// `--optional` and `--required` override whether the listed fields are required,
+/// and `--mode` picks which check the failure report is for (default strict).
+/// `--emit` writes the valid passports to a new batch file in a canonical form.
+/// `--clean` writes every passport to a new batch file with safe repairs applied,
+/// and lists the repairs along with the unsafe ones that need review.
+/// `--strict-parse` stops at the first malformed token or duplicate key instead of
+/// reporting it and carrying on.
+/// `--input` reads passports from FILE instead of `inputs/d4`, in the batch format
+/// or as converted by `--export-jsonl` and `--export-csv`.
+/// `--stream` validates a batch format input one passport at a time, in N threads,
+/// and only reports counts. It can't be combined with another `--input-format`,
+/// `--strict-parse`, `--export-jsonl`, `--export-csv`, `--emit` or `--clean`.
+/// `--generate` prints a random batch of N passports instead, in the proportions
+/// V valid, M missing a field and O with a field out of range, and prints the kind
+/// of each to stderr.
+pub fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let arg_value = |flag: &str| args.iter().position(|arg| arg == flag).and_then(|i| args.get(i + 1));
+    let mut schema = match arg_value("--schema") {
+        Some(path) => {
+            let text = read_to_string(path).expect("failed to read schema");
+            Schema::parse(&text).unwrap_or_else(|e| panic!("bad schema {}: {}", path, e))
+        },
+        None => Schema::default(),
+    };
+    for (flag, required) in [("--optional", false), ("--required", true)].iter() {
+        for name in arg_value(flag).iter().flat_map(|names| names.split(',')) {
+            schema.set_required(name, *required);
+        }
+    }
+    let report_mode = match arg_value("--mode").map(String::as_str) {
+        None | Some("strict") => Mode::Strict,
+        Some("presence") => Mode::Presence,
+        Some(other) => panic!("unknown mode {}, expected presence or strict", other),
+    };
+
+    if let Some(n) = arg_value("--generate") {
+        let n = n.parse::<usize>().expect("--generate takes a number of passports");
+        let seed = arg_value("--seed").map_or(2020, |s| s.parse::<u64>().expect("--seed takes a number"));
+        let weights: Vec<usize> = arg_value("--mix").map_or("1,1,1", String::as_str).split(',')
+            .map(|w| w.parse().expect("--mix takes three weights V,M,O"))
+            .collect();
+        let mix = match weights.as_slice() {
+            [valid, missing_field, out_of_range] if valid + missing_field + out_of_range > 0 =>
+                Mix { valid: *valid, missing_field: *missing_field, out_of_range: *out_of_range },
+            _ => panic!("--mix takes three weights V,M,O, not all zero"),
+        };
+        let mut rng = Rng::new(seed);
+        let batch = generate_batch(&schema, n, &mix, &mut rng).unwrap_or_else(|e| panic!("{}", e));
+        for (kind, _) in batch.iter() {
+            eprintln!("{:?}", kind);
+        }
+        let passports: Vec<Fields> = batch.into_iter().map(|(_, passport)| passport).collect();
+        print!("{}", to_batch_fuzzed(&passports, &mut rng));
+        return;
+    }
+    let input_path = arg_value("--input").map_or("inputs/d4", String::as_str);
+    if args.iter().any(|arg| arg == "--stream") {
+        if arg_value("--input-format").is_some_and(|format| format != "batch") {
+            panic!("--stream only reads the batch format");
+        }
+        let unsupported = ["--strict-parse", "--export-jsonl", "--export-csv", "--emit", "--clean"];
+        if let Some(flag) = unsupported.iter().find(|flag| args.iter().any(|arg| arg == *flag)) {
+            panic!("{} can't be combined with --stream", flag);
+        }
+        let threads = arg_value("--threads").map_or(1, |n| n.parse().expect("--threads takes a number"));
+        stream_main(input_path, &schema, report_mode, threads);
+        return;
+    }
+    let input = read_to_string(input_path).unwrap_or_else(|e| panic!("failed to read {}: {}", input_path, e));
+    let imported = match arg_value("--input-format").map_or("batch", String::as_str) {
+        "batch" => Ok(None),
+        "jsonl" => from_json_lines(&input).map(Some),
+        "csv" => from_csv(&input).map(Some),
+        other => panic!("unknown input format {}, expected batch, jsonl or csv", other),
+    };
+    let input = match imported.unwrap_or_else(|e| panic!("failed to import {}: {}", input_path, e)) {
+        Some(passports) => to_batch(&passports),
+        None => input,
+    };
+    if args.iter().any(|arg| arg == "--strict-parse") {
+        if let Err(e) = parse_passports(&input) {
+            panic!("failed to parse input: {}", e);
+        }
+    }
+    let records = parse_passports_lenient(&input);
+    for record in records.iter() {
+        for error in record.errors.iter() {
+            println!("Parse error in {}: {}", record, error);
+        }
+    }
+    let parse_result: Vec<&Passport> = records.iter().map(|record| &record.passport).collect();
+    let mut all_errors = Vec::new();
+    for (record, passwd) in records.iter().zip(parse_result.iter()) {
+        if let Err(errors) = passwd.validate_detailed(&schema, report_mode) {
+            let reasons: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+            println!("Invalid {}: {}", record, reasons.join("; "));
+            all_errors.extend(errors);
+        }
+    }
+    println!("Most common failures:");
+    for (field, kind, n) in failure_summary(all_errors.iter()) {
+        println!("{:>6}  {} {}", n, field, kind);
+    }
+    let count = |mode| parse_result.iter().filter(|p| p.validate(&schema, mode)).count();
+    println!("Part 1, passports with required fields: {}", count(Mode::Presence));
+    println!("Part 2, passports with valid fields: {}", count(Mode::Strict));
+
+    if let Some(path) = arg_value("--export-jsonl") {
+        std::fs::write(path, to_json_lines(&parse_result, &schema)).expect("failed to write JSON Lines");
+    }
+    if let Some(path) = arg_value("--export-csv") {
+        std::fs::write(path, to_csv(&parse_result, &schema)).expect("failed to write CSV");
+    }
+
+    if let Some(path) = arg_value("--emit") {
+        let valid: Vec<TypedPassport> = parse_result.iter()
+            .filter(|p| p.validate(&schema, Mode::Strict))
+            .filter_map(|p| TypedPassport::parse(p).ok())
+            .collect();
+        std::fs::write(path, to_batch(&valid)).expect("failed to write batch");
+    }
+
+    if let Some(path) = arg_value("--clean") {
+        let cleaned: Vec<Normalized> = parse_result.iter().map(|p| Normalized::new(p, &schema)).collect();
+        for (record, normalized) in records.iter().zip(cleaned.iter()) {
+            for repair in normalized.applied.iter() {
+                println!("Repaired {}: {}", record, repair);
+            }
+            for repair in normalized.suggested.iter() {
+                println!("Suggested for {}: {}", record, repair);
+            }
+        }
+        std::fs::write(path, to_batch(&cleaned)).expect("failed to write batch");
+    }
+}
+
+/// A problem with a single `key:value` token of a batch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TokenError {
+    /// A token that isn't `key:value`, which is skipped
+    Malformed { line_num: usize, token: String },
+    /// A key seen earlier in the same passport; the first value is kept
+    DuplicateKey { line_num: usize, key: String },
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenError::Malformed { line_num, token } => write!(f, "line {}: malformed token `{}`", line_num, token),
+            TokenError::DuplicateKey { line_num, key } => write!(f, "line {}: duplicate key {}", line_num, key),
+        }
+    }
+}
+
+impl TokenError {
+    /// Move the error `lines` lines down the batch
+    fn offset(self, lines: usize) -> Self {
+        match self {
+            TokenError::Malformed { line_num, token } => TokenError::Malformed { line_num: line_num + lines, token },
+            TokenError::DuplicateKey { line_num, key } => TokenError::DuplicateKey { line_num: line_num + lines, key },
         }
     }
-    println!("Number of valid passports: {}", count);
 }
 
-fn parse_passports(input: &str) -> Result<Vec<Passport>, &'static str> {
-    let mut result = Vec::new();
-    let mut map: HashMap<&str, &str> = HashMap::new();
-    let input = input.trim();
-    for line in input.lines() {
+/// A passport read by `parse_passports_lenient`, with its position in the batch.
+#[derive(Debug)]
+struct Record<'a> {
+    /// 0-based index of the passport in the batch
+    index: usize,
+    /// 1-based line number the passport starts on
+    line_num: usize,
+    passport: Passport<'a>,
+    errors: Vec<TokenError>,
+}
+
+impl<'a> fmt::Display for Record<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "passport {} (line {})", self.index, self.line_num)
+    }
+}
+
+/// Parse a batch of passports separated by blank lines. Problems with tokens are
+/// recorded on the passport they belong to and parsing continues.
+fn parse_passports_lenient(input: &str) -> Vec<Record<'_>> {
+    let mut result: Vec<Record> = Vec::new();
+    let mut current: Option<Record> = None;
+    for (idx, line) in input.lines().enumerate() {
+        let line_num = idx + 1;
         let tokens: Vec<&str> = line.split_whitespace().collect();
-        if tokens.len() == 0 {
+        if tokens.is_empty() {
             // blank line encountered
-            let tmp_map = map.clone();
-            map.clear();
-            result.push(Passport::new(tmp_map));
+            result.extend(current.take());
             continue;
         }
 
-        // parse the tokens and insert into `map`
+        // parse the tokens and insert into the current passport
+        let record = current.get_or_insert_with(|| Record {
+            index: result.len(),
+            line_num,
+            passport: Passport::new(HashMap::new()),
+            errors: Vec::new(),
+        });
         for tok in tokens.iter() {
-            let (key, val) = parse_token(tok)?;
-            if let Some(_) = map.insert(key, val)  {
-                return Err("duplicate key encounterd");
+            match parse_token(tok) {
+                None => record.errors.push(TokenError::Malformed { line_num, token: tok.to_string() }),
+                Some((key, _)) if record.passport.0.contains_key(key) =>
+                    record.errors.push(TokenError::DuplicateKey { line_num, key: key.to_string() }),
+                Some((key, val)) => {
+                    record.passport.0.insert(key, val);
+                },
             }
         }
     }
     // push last entry before EOF
-    if map.len() != 0 {
-        result.push(Passport::new(map));
+    result.extend(current);
+    result
+}
+
+/// The unparsed text of one passport in a batch, with its position in the batch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct RawRecord {
+    /// 0-based index of the passport in the batch
+    index: usize,
+    /// 1-based line number the passport starts on
+    line_num: usize,
+    text: String,
+}
+
+/// Iterator over the passports of a batch read from a reader, holding only one
+/// passport in memory at a time.
+struct BatchReader<R> {
+    lines: io::Lines<R>,
+    line_num: usize,
+    index: usize,
+}
+
+impl<R: BufRead> BatchReader<R> {
+    fn new(reader: R) -> Self {
+        BatchReader { lines: reader.lines(), line_num: 0, index: 0 }
     }
-    Ok(result)
 }
 
-fn parse_token(tok: &str) -> Result<(&str, &str), &'static str> {
+impl<R: BufRead> Iterator for BatchReader<R> {
+    type Item = io::Result<RawRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record: Option<RawRecord> = None;
+        for line in self.lines.by_ref() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e)),
+            };
+            self.line_num += 1;
+            if line.trim().is_empty() {
+                if record.is_some() {
+                    break;
+                }
+                continue;
+            }
+            let (index, line_num) = (self.index, self.line_num);
+            let record = record.get_or_insert_with(|| RawRecord { index, line_num, text: String::new() });
+            record.text.push_str(&line);
+            record.text.push('\n');
+        }
+        if record.is_some() {
+            self.index += 1;
+        }
+        record.map(Ok)
+    }
+}
+
+/// The outcome of parsing and validating one passport of a batch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Checked {
+    index: usize,
+    line_num: usize,
+    parse_errors: Vec<TokenError>,
+    errors: Vec<FieldError>,
+}
+
+impl RawRecord {
+    fn check(&self, schema: &Schema, mode: Mode) -> Checked {
+        let mut checked = Checked { index: self.index, line_num: self.line_num, parse_errors: Vec::new(), errors: Vec::new() };
+        // the text has no blank lines, so is a single passport
+        for record in parse_passports_lenient(&self.text) {
+            checked.parse_errors.extend(record.errors.into_iter().map(|e| e.offset(self.line_num - 1)));
+            checked.errors.extend(record.passport.validate_detailed(schema, mode).err().unwrap_or_default());
+        }
+        checked
+    }
+}
+
+/// Parse and validate the passports read from `reader` one at a time, passing each
+/// outcome to `on_checked`. With more than one thread, passports are validated in
+/// parallel and outcomes arrive in no particular order.
+fn validate_stream<R, F>(reader: R, schema: &Schema, mode: Mode, threads: usize, mut on_checked: F) -> io::Result<()>
+    where R: BufRead, F: FnMut(Checked) {
+    if threads <= 1 {
+        for raw in BatchReader::new(reader) {
+            on_checked(raw?.check(schema, mode));
+        }
+        return Ok(());
+    }
+    // bound the passports waiting to be validated, to bound memory
+    let (work_tx, work_rx) = mpsc::sync_channel::<RawRecord>(threads * 4);
+    let work_rx = Mutex::new(work_rx);
+    let (done_tx, done_rx) = mpsc::channel::<Checked>();
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let (work_rx, done_tx) = (&work_rx, done_tx.clone());
+            scope.spawn(move || {
+                while let Ok(raw) = work_rx.lock().unwrap().recv() {
+                    if done_tx.send(raw.check(schema, mode)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(done_tx);
+        for raw in BatchReader::new(reader) {
+            work_tx.send(raw?).expect("validation threads stopped");
+            done_rx.try_iter().for_each(&mut on_checked);
+        }
+        drop(work_tx);
+        done_rx.iter().for_each(&mut on_checked);
+        Ok(())
+    })
+}
+
+/// Parse a batch of passports, failing on the first problem with a token.
+fn parse_passports(input: &str) -> Result<Vec<Passport<'_>>, String> {
+    parse_passports_lenient(input).into_iter()
+        .map(|record| match record.errors.first() {
+            None => Ok(record.passport),
+            Some(error) => Err(format!("{}: {}", record, error)),
+        })
+        .collect()
+}
+
+fn parse_token(tok: &str) -> Option<(&str, &str)> {
     let splits: Vec<&str> = tok.split(':').collect();
     if splits.len() == 2 {
-        Ok((splits[0], splits[1]))
+        Some((splits[0], splits[1]))
     } else {
-        Err("error parsing token")
+        None
     }
 }
 
+/// Validate the batch at `path` with `validate_stream` and print a summary.
+fn stream_main(path: &str, schema: &Schema, mode: Mode, threads: usize) {
+    let file = File::open(path).unwrap_or_else(|e| panic!("failed to open {}: {}", path, e));
+    let (mut passports, mut valid, mut failures) = (0, 0, FailureCounts::default());
+    validate_stream(BufReader::new(file), schema, mode, threads, |checked| {
+        passports += 1;
+        for error in checked.parse_errors.iter() {
+            println!("Parse error in passport {} (line {}): {}", checked.index, checked.line_num, error);
+        }
+        if checked.errors.is_empty() {
+            valid += 1;
+        }
+        checked.errors.iter().for_each(|error| failures.add(error));
+    }).unwrap_or_else(|e| panic!("failed to read {}: {}", path, e));
+    println!("Most common failures:");
+    for (field, kind, n) in failures.most_common() {
+        println!("{:>6}  {} {}", n, field, kind);
+    }
+    println!("Passports: {}", passports);
+    println!("Valid passports ({:?} mode): {}", mode, valid);
+}
+
 #[cfg(test)]
 mod test_d4 {
-    use crate::parse_passports;
+    use crate::*;
 
     const TEST_INPUT: &'static str =
         "ecl:gry pid:860033327 eyr:2020 hcl:#fffffd
@@ -229,9 +1428,300 @@ mod test_d4 {
         assert!(p.is_ok());
         let p = p.unwrap();
         assert!(p[0].0.contains_key("ecl"));
-        assert!(p[0].validate());
-        assert!(!p[1].validate());
-        assert!(p[2].validate());
-        assert!(!p[3].validate());
+        let schema = Schema::default();
+        assert!(p[0].validate(&schema, Mode::Strict));
+        assert!(!p[1].validate(&schema, Mode::Strict));
+        assert!(p[2].validate(&schema, Mode::Strict));
+        assert!(!p[3].validate(&schema, Mode::Strict));
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = Schema::default();
+        let check = |field: &str, value: &str| schema.field(field).unwrap().rule.check(value);
+        assert!(check("byr", "2002"));
+        assert!(!check("byr", "2003"));
+        assert!(check("hgt", "60in"));
+        assert!(check("hgt", "190cm"));
+        assert!(!check("hgt", "190in"));
+        assert!(!check("hgt", "190"));
+        assert!(check("hcl", "#123abc"));
+        assert!(!check("hcl", "#123abz"));
+        assert!(!check("hcl", "123abc"));
+        assert!(check("ecl", "brn"));
+        assert!(!check("ecl", "wat"));
+        assert!(check("pid", "000000001"));
+        assert!(!check("pid", "0123456789"));
+
+        // cid becomes required, and the north pole accepts taller visitors
+        let schema = Schema::parse("
+            # custom rules
+            hgt required units cm:150-250
+            cid required int 1-999
+        ").unwrap();
+        let p = parse_passports("hgt:240cm cid:12\n\nhgt:240cm\n\nhgt:70in cid:12").unwrap();
+        assert!(p[0].validate(&schema, Mode::Strict));
+        assert!(!p[1].validate(&schema, Mode::Strict));
+        assert!(!p[2].validate(&schema, Mode::Strict));
+        assert!(matches!(schema.field("hgt").unwrap().rule, FieldRule::Units(_)));
+
+        assert!(matches!(Schema::parse("byr required int 2002-1920"), Err(SchemaError::Malformed(1, _))));
+        assert!(matches!(Schema::parse("hcl required regex ("), Err(SchemaError::BadPattern(1, _))));
+        assert!(matches!(Schema::parse("a optional any\na required any"), Err(SchemaError::DuplicateField(_))));
+    }
+
+    #[test]
+    fn test_validate_detailed() {
+        let schema = Schema::default();
+        let p = parse_passports("hgt:190in byr:2003 ecl:wat pid:0123456789 cid:1\n\n\
+                                 hgt:190 hcl:123abc iyr:20x0").unwrap();
+        let errors = p[0].validate_detailed(&schema, Mode::Strict).unwrap_err();
+        let reasons: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(reasons, vec![
+            "iyr: missing",
+            "eyr: missing",
+            "hcl: missing",
+            "byr: 2003 exceeds 2002",
+            "hgt: 190in exceeds 76in",
+            "ecl: wat is not one of amb, blu, brn, gry, grn, hzl, oth",
+            "pid: 0123456789 doesn't match ^[0-9]{9}$",
+        ]);
+        let errors2 = p[1].validate_detailed(&schema, Mode::Strict).unwrap_err();
+        assert!(errors2.contains(&FieldError::Invalid {
+            field: "hgt".to_string(),
+            value: "190".to_string(),
+            reason: Reason::UnknownUnit(vec!["cm".to_string(), "in".to_string()]),
+        }));
+        assert!(errors2.iter().any(|e| e.to_string() == "iyr: 20x0 is not a number"));
+
+        let summary = failure_summary(errors.iter().chain(errors2.iter()));
+        assert_eq!(summary[0], ("eyr".to_string(), "missing", 2));
+        assert!(summary.contains(&("hgt".to_string(), "unknown unit", 1)));
+        assert_eq!(summary.iter().map(|s| s.2).sum::<usize>(), errors.len() + errors2.len());
+    }
+
+    #[test]
+    fn test_modes() {
+        let mut schema = Schema::default();
+        let p = parse_passports("ecl:gry pid:860033327 eyr:2020 hcl:#fffffd byr:1937 iyr:2017 hgt:183cm\n\n\
+                                 ecl:gry pid:860033327 eyr:2020 hcl:fffffd byr:1937 iyr:2017 hgt:183cm cid:1\n\n\
+                                 ecl:gry pid:860033327 eyr:2020 hcl:#fffffd byr:1937 iyr:2017").unwrap();
+        let valid = |schema: &Schema, mode| p.iter().map(|p| p.validate(schema, mode)).collect::<Vec<bool>>();
+        assert_eq!(valid(&schema, Mode::Presence), vec![true, true, false]);
+        assert_eq!(valid(&schema, Mode::Strict), vec![true, false, false]);
+
+        schema.set_required("cid", true);
+        schema.set_required("hgt", false);
+        assert_eq!(valid(&schema, Mode::Presence), vec![false, true, false]);
+        assert_eq!(valid(&schema, Mode::Strict), vec![false, false, false]);
+        schema.set_required("cid", false);
+        assert_eq!(valid(&schema, Mode::Strict), vec![true, false, true]);
+        // the optional hgt is still checked when present
+        let p = parse_passports("ecl:gry pid:860033327 eyr:2020 hcl:#fffffd byr:1937 iyr:2017 hgt:1cm").unwrap();
+        assert!(p[0].validate(&schema, Mode::Presence));
+        assert!(!p[0].validate(&schema, Mode::Strict));
+    }
+
+    #[test]
+    fn test_typed_passport() {
+        let p = parse_passports(TEST_INPUT).unwrap();
+        let typed = TypedPassport::parse(&p[0]).unwrap();
+        assert_eq!(typed.byr, 1937);
+        assert_eq!(typed.hgt, Height::Cm(183));
+        assert_eq!(typed.hcl, Rgb(0xff, 0xff, 0xfd));
+        assert_eq!(typed.ecl, EyeColor::Gry);
+        assert_eq!(typed.pid, PassportId([8, 6, 0, 0, 3, 3, 3, 2, 7]));
+        assert_eq!(typed.cid, Some("147".to_string()));
+        assert_eq!(typed.to_string(),
+                   "byr:1937 iyr:2017 eyr:2020 hgt:183cm hcl:#fffffd ecl:gry pid:860033327 cid:147");
+
+        // missing hgt
+        assert_eq!(TypedPassport::parse(&p[1]), Err(vec![FieldError::Missing("hgt".to_string())]));
+        let bad = parse_passports("byr:x iyr:2017 eyr:2020 hgt:183 hcl:#fffffd ecl:wat pid:000000001 zzz:1").unwrap();
+        let errors = TypedPassport::parse(&bad[0]).unwrap_err();
+        let kinds: Vec<(&str, &str)> = errors.iter()
+            .map(|e| (e.field(), e.kind()))
+            .collect();
+        assert_eq!(kinds, vec![("byr", "not a number"), ("hgt", "unknown unit"), ("ecl", "unknown value")]);
+
+        // round trip through the batch format, keeping unknown fields and leading zeroes
+        let input = "pid:087499704 hgt:74in ecl:grn iyr:2012 eyr:2030 byr:1980 hcl:#623a2f zzz:1";
+        let typed = TypedPassport::parse(&parse_passports(input).unwrap()[0]).unwrap();
+        assert_eq!(typed.pid.to_string(), "087499704");
+        let batch = to_batch(&[typed.clone(), typed.clone()]);
+        let reparsed = parse_passports(&batch).unwrap();
+        assert_eq!(reparsed.len(), 2);
+        assert_eq!(TypedPassport::parse(&reparsed[1]), Ok(typed));
+    }
+
+    #[test]
+    fn test_parse_lenient() {
+        let input = "\n\
+            byr:1937 iyr:2017 oops\n\
+            byr:1938 cid:1\n\
+            \n\
+            \n\
+            hgt:183cm\n\
+            \n\
+            a:b:c ecl:gry";
+        let records = parse_passports_lenient(input);
+        assert_eq!(records.len(), 3);
+        assert_eq!((records[0].index, records[0].line_num), (0, 2));
+        assert_eq!(records[0].errors, vec![
+            TokenError::Malformed { line_num: 2, token: "oops".to_string() },
+            TokenError::DuplicateKey { line_num: 3, key: "byr".to_string() },
+        ]);
+        // the first value of a duplicate key is kept and parsing goes on
+        assert_eq!(records[0].passport.0.get("byr"), Some(&"1937"));
+        assert_eq!(records[0].passport.0.len(), 3);
+        assert_eq!((records[1].index, records[1].line_num), (1, 6));
+        assert!(records[1].errors.is_empty());
+        assert_eq!(records[2].line_num, 8);
+        assert_eq!(records[2].passport.0.get("ecl"), Some(&"gry"));
+        assert_eq!(records[2].errors.len(), 1);
+
+        assert_eq!(parse_passports(input).unwrap_err(), "passport 0 (line 2): line 2: malformed token `oops`");
+        assert_eq!(parse_passports(TEST_INPUT).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_normalize() {
+        let schema = Schema::default();
+        let repairs = |field: &str, value: &str| schema.field(field).unwrap().rule.repairs(value);
+        assert_eq!(repairs("hcl", "123abc"), vec![("#123abc".to_string(), true)]);
+        assert_eq!(repairs("ecl", "BRN"), vec![("brn".to_string(), true)]);
+        assert_eq!(repairs("hgt", "170"), vec![("170cm".to_string(), false)]);
+        assert_eq!(repairs("hgt", "70"), vec![("70in".to_string(), false)]);
+        assert_eq!(repairs("pid", "0123456789"), vec![("123456789".to_string(), false)]);
+        assert_eq!(repairs("byr", "2003"), vec![]);
+
+        let p = parse_passports("zzz:1 hgt:170 pid:0123456789 hcl:123abc ecl:BRN byr:1937 iyr:2017 eyr:2020").unwrap();
+        assert!(!p[0].validate(&schema, Mode::Strict));
+        let normalized = Normalized::new(&p[0], &schema);
+        assert_eq!(normalized.to_string(),
+                   "byr:1937 iyr:2017 eyr:2020 hgt:170 hcl:#123abc ecl:brn pid:0123456789 zzz:1");
+        let fields = |repairs: &[Repair]| repairs.iter().map(|r| r.field.clone()).collect::<Vec<String>>();
+        assert_eq!(fields(&normalized.applied), vec!["hcl", "ecl"]);
+        assert_eq!(fields(&normalized.suggested), vec!["hgt", "pid"]);
+        assert_eq!(normalized.suggested[0].to_string(), "hgt: 170 -> 170cm");
+
+        // applying the suggestions too gives a valid passport
+        let batch = to_batch(&[normalized]).replace("170", "170cm").replace("0123456789", "123456789");
+        assert!(parse_passports(&batch).unwrap()[0].validate(&schema, Mode::Strict));
+        // valid passports are left alone
+        let p = parse_passports(TEST_INPUT).unwrap();
+        assert!(Normalized::new(&p[0], &schema).applied.is_empty());
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let schema = Schema::default();
+        let p = parse_passports(TEST_INPUT).unwrap();
+        let passports: Vec<&Passport> = p.iter().collect();
+        let jsonl = to_json_lines(&passports, &schema);
+        assert_eq!(jsonl.lines().nth(1).unwrap(),
+                   concat!(r##"{"byr": "1929", "iyr": "2013", "eyr": "2023", "hcl": "#cfa07d", "##,
+                           r##""ecl": "amb", "pid": "028048884", "cid": "350"}"##));
+        let imported = from_json_lines(&jsonl).unwrap();
+        assert_eq!(imported.len(), 4);
+        assert_eq!(imported[1].0[0], ("byr".to_string(), "1929".to_string()));
+        let batch = to_batch(&imported);
+        let reparsed = parse_passports(&batch).unwrap();
+        assert_eq!(reparsed.iter().map(|p| &p.0).collect::<Vec<_>>(), p.iter().map(|p| &p.0).collect::<Vec<_>>());
+
+        let bad = "{\"byr\": \"1937\"}\n\n{\"hgt\": \"183 cm\"}";
+        assert_eq!(from_json_lines(bad).unwrap_err().line_num, 3);
+        assert_eq!(from_json_lines("{\"byr\": 1937, \"zzz\": 1}").unwrap()[0].to_string(), "byr:1937 zzz:1");
+    }
+
+    #[test]
+    fn test_csv() {
+        let schema = Schema::default();
+        let p = parse_passports("byr:1937 zzz:a,\"b cid:147 aaa:1\n\nhgt:183cm").unwrap();
+        let passports: Vec<&Passport> = p.iter().collect();
+        let csv = to_csv(&passports, &schema);
+        assert_eq!(csv, "byr,iyr,eyr,hgt,hcl,ecl,pid,cid,extras\n\
+                         1937,,,,,,,147,\"aaa:1 zzz:a,\"\"b\"\n\
+                         ,,,183cm,,,,,\n");
+        let imported = from_csv(&csv).unwrap();
+        assert_eq!(imported[0].to_string(), "byr:1937 cid:147 aaa:1 zzz:a,\"b");
+        assert_eq!(imported[1].to_string(), "hgt:183cm");
+
+        // columns in any order, quoted line breaks, unknown columns kept as fields
+        let csv = "pid,\"note\",extras\r\n000000001,\"x\",\r\n\n\"0\n\",y,cid:1\n";
+        assert_eq!(parse_csv(csv).unwrap().iter().map(|(line_num, _)| *line_num).collect::<Vec<_>>(), vec![1, 2, 4]);
+        assert_eq!(from_csv(csv).unwrap_err(), ImportError {
+            line_num: 4,
+            reason: "`pid:0\n` can't be written as a batch token".to_string(),
+        });
+        let imported = from_csv(&csv.replace("\"0\n\"", "2")).unwrap();
+        assert_eq!(imported[0].to_string(), "pid:000000001 note:x");
+        assert_eq!(imported[1].to_string(), "pid:2 note:y cid:1");
+        assert_eq!(from_csv("a,b\n1\n").unwrap_err().line_num, 2);
+        assert_eq!(from_csv("a\n\"1\n").unwrap_err().reason, "unterminated quoted cell");
+    }
+
+    #[test]
+    fn test_validate_stream() {
+        let reader = BatchReader::new(io::Cursor::new("\n\nbyr:1 oops\n\n\niyr:2\nbyr:3\n"));
+        let raws: Vec<RawRecord> = reader.map(Result::unwrap).collect();
+        assert_eq!(raws, vec![
+            RawRecord { index: 0, line_num: 3, text: "byr:1 oops\n".to_string() },
+            RawRecord { index: 1, line_num: 6, text: "iyr:2\nbyr:3\n".to_string() },
+        ]);
+        let checked = raws[0].check(&Schema::default(), Mode::Presence);
+        assert_eq!(checked.parse_errors, vec![TokenError::Malformed { line_num: 3, token: "oops".to_string() }]);
+        assert_eq!(checked.errors.len(), 6);
+
+        // a large batch gives the same outcomes however many threads validate it
+        let batch = format!("{}\n\n", TEST_INPUT).repeat(500);
+        let schema = Schema::default();
+        let outcomes = |threads: usize| {
+            let mut outcomes = Vec::new();
+            validate_stream(io::Cursor::new(batch.as_str()), &schema, Mode::Strict, threads, |c| outcomes.push(c)).unwrap();
+            outcomes.sort_by_key(|c| c.index);
+            outcomes
+        };
+        let sequential = outcomes(1);
+        assert_eq!(sequential.len(), 2000);
+        assert_eq!(sequential.iter().filter(|c| c.errors.is_empty()).count(), 1000);
+        assert_eq!(sequential[5].line_num, 18);
+        assert_eq!(outcomes(4), sequential);
+    }
+
+    #[test]
+    fn test_generate_batch() {
+        let schema = Schema::default();
+        let mix = Mix { valid: 2, missing_field: 1, out_of_range: 1 };
+        let batch = generate_batch(&schema, 400, &mix, &mut Rng::new(4)).unwrap();
+        assert_eq!(batch, generate_batch(&schema, 400, &mix, &mut Rng::new(4)).unwrap());
+        for kind in [Kind::Valid, Kind::MissingField, Kind::OutOfRange].iter() {
+            assert!(batch.iter().filter(|(k, _)| k == kind).count() > 50);
+        }
+        let (kinds, passports): (Vec<Kind>, Vec<Fields>) = batch.into_iter().unzip();
+        let text = to_batch_fuzzed(&passports, &mut Rng::new(5));
+        let parsed = parse_passports(&text).unwrap();
+        assert_eq!(parsed.len(), kinds.len());
+        for (passport, kind) in parsed.iter().zip(kinds.iter()) {
+            for mode in [Mode::Presence, Mode::Strict].iter() {
+                assert_eq!(passport.validate(&schema, *mode), kind.is_valid(*mode), "{:?}: {:?}", kind, passport);
+            }
+        }
+
+        // a required field with an unknown pattern can't be generated, whatever its name
+        let schema = Schema::parse("xyz required regex ^x$").unwrap();
+        assert!(generate_batch(&schema, 1, &mix, &mut Rng::new(4)).is_err());
+        let schema = Schema::parse("hcl required regex ^#[0-9a-f]{3}$").unwrap();
+        assert!(generate_batch(&schema, 1, &mix, &mut Rng::new(4)).is_err());
+        // built-in patterns can be generated for any field
+        let schema = Schema::parse("xyz required regex ^[0-9]{9}$\nbyr required int 1920-2002").unwrap();
+        let batch = generate_batch(&schema, 20, &mix, &mut Rng::new(4)).unwrap();
+        assert!(batch.iter().all(|(kind, passport)| {
+            Passport::new(passport.0.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect())
+                .validate(&schema, Mode::Strict) == kind.is_valid(Mode::Strict)
+        }));
+        for spec in Schema::default().fields.iter().filter(|spec| spec.name == "hcl" || spec.name == "pid") {
+            assert!(spec.sample(&mut Rng::new(4)).is_some(), "{}", spec.name);
+        }
     }
 }
@@ -1,63 +1,434 @@
+#![feature(test)]
 extern crate aoc_2020;
+extern crate test;
 
 use aoc_2020::util;
 use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
 
+/// The seats of an aircraft and how boarding passes pick them out.
+///
+/// A boarding pass has `log2(rows)` letters choosing the front or back half of
+/// the remaining rows, then `log2(cols)` letters choosing the left or right
+/// half of the remaining columns. The seat id is `row * row_factor + col`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Layout {
+    rows: usize,
+    cols: usize,
+    /// Letters for the front and back halves
+    row_letters: [char; 2],
+    /// Letters for the left and right halves
+    col_letters: [char; 2],
+    row_factor: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LayoutError {
+    /// Rows and columns must be powers of two to be bisected
+    NotPowerOfTwo(usize),
+    /// Both halves are chosen by the same letter
+    SameLetters(char),
+    NotAscii(char),
+    /// Seat ids wouldn't be unique, since `row_factor < cols`
+    RowFactorTooSmall(usize),
+    /// A description that isn't `ROWSxCOLS ROW_LETTERS COL_LETTERS [ROW_FACTOR]`
+    Malformed(String),
+}
+
+impl Layout {
+    pub fn new(rows: usize, cols: usize, row_letters: [char; 2], col_letters: [char; 2], row_factor: usize)
+               -> Result<Self, LayoutError> {
+        if let Some(n) = [rows, cols].iter().find(|n| !n.is_power_of_two()) {
+            return Err(LayoutError::NotPowerOfTwo(*n));
+        }
+        if let Some(letters) = [row_letters, col_letters].iter().find(|letters| letters[0] == letters[1]) {
+            return Err(LayoutError::SameLetters(letters[0]));
+        }
+        if let Some(letter) = row_letters.iter().chain(col_letters.iter()).find(|letter| !letter.is_ascii()) {
+            return Err(LayoutError::NotAscii(*letter));
+        }
+        if row_factor < cols {
+            return Err(LayoutError::RowFactorTooSmall(row_factor));
+        }
+        Ok(Layout { rows, cols, row_letters, col_letters, row_factor })
+    }
+
+    /// Number of letters choosing the row
+    fn row_len(&self) -> usize {
+        self.rows.trailing_zeros() as usize
+    }
+
+    /// Number of letters choosing the column
+    fn col_len(&self) -> usize {
+        self.cols.trailing_zeros() as usize
+    }
+
+    /// Decode a pass by reading it as a binary number, with the second letter of
+    /// each pair as 1, without allocating.
+    pub fn decode(&self, pass: &str) -> Result<BoardingPass, PassError> {
+        let expected = self.row_len() + self.col_len();
+        let bytes = pass.as_bytes();
+        let found = if pass.is_ascii() { bytes.len() } else { pass.chars().count() };
+        if found != expected {
+            return Err(PassError::BadLength { found, expected });
+        }
+        // letters are ASCII, so the first non-letter byte starts a char
+        let row_len = self.row_len();
+        let mut n = 0;
+        for (pos, byte) in bytes.iter().enumerate() {
+            let letters = if pos < row_len { self.row_letters } else { self.col_letters };
+            let bit = if *byte == letters[1] as u8 {
+                1
+            } else if *byte == letters[0] as u8 {
+                0
+            } else {
+                return Err(PassError::BadLetter(pos, pass[pos..].chars().next().unwrap()));
+            };
+            n = (n << 1) | bit;
+        }
+        let (row, col) = (n >> self.col_len(), n & (self.cols - 1));
+        Ok(BoardingPass { row, col, seat_id: row * self.row_factor + col })
+    }
+
+    /// Decode a pass by bisecting the rows and columns with `bsp_find`. This is
+    /// slower than `decode`, but follows the puzzle description.
+    pub fn decode_bsp(&self, pass: &str) -> Result<BoardingPass, PassError> {
+        let len = pass.chars().count();
+        if len != self.row_len() + self.col_len() {
+            return Err(PassError::BadLength { found: len, expected: self.row_len() + self.col_len() });
+        }
+        let split = pass.char_indices().nth(self.row_len()).map_or(pass.len(), |(idx, _)| idx);
+        let (row_cmd, col_cmd) = pass.split_at(split);
+        let row = bsp_find(row_cmd, 0, self.rows - 1, self.row_letters)?;
+        let col = bsp_find(col_cmd, 0, self.cols - 1, self.col_letters).map_err(|e| match e {
+            PassError::BadLetter(pos, letter) => PassError::BadLetter(self.row_len() + pos, letter),
+            e => e,
+        })?;
+        Ok(BoardingPass { row, col, seat_id: row * self.row_factor + col })
+    }
+
+    /// Return the boarding pass for the seat with the given id
+    pub fn encode(&self, seat_id: usize) -> Result<String, PassError> {
+        let (row, col) = (seat_id / self.row_factor, seat_id % self.row_factor);
+        if row >= self.rows || col >= self.cols {
+            return Err(PassError::NoSuchSeat(seat_id));
+        }
+        let letters = |n: usize, len: usize, letters: [char; 2]| {
+            (0..len).rev().map(move |bit| letters[(n >> bit) & 1])
+        };
+        Ok(letters(row, self.row_len(), self.row_letters)
+            .chain(letters(col, self.col_len(), self.col_letters))
+            .collect())
+    }
+}
+
+/// The part one aircraft: 128 rows of 8 seats, chosen by `F`/`B` and `L`/`R`.
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::new(128, 8, ['F', 'B'], ['L', 'R'], 8).unwrap()
+    }
+}
+
+/// Parse descriptions like `128x8 FB LR 8`; the row factor defaults to the
+/// number of columns.
+impl FromStr for Layout {
+    type Err = LayoutError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed = || LayoutError::Malformed(s.to_string());
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        if parts.len() != 3 && parts.len() != 4 {
+            return Err(malformed());
+        }
+        let dims: Vec<usize> = parts[0].split('x').map(str::parse).collect::<Result<_, _>>().map_err(|_e| malformed())?;
+        let letters = |part: &str| match part.chars().collect::<Vec<char>>().as_slice() {
+            [low, high] => Ok([*low, *high]),
+            _ => Err(malformed()),
+        };
+        match dims.as_slice() {
+            [rows, cols] => {
+                let row_factor = match parts.get(3) {
+                    Some(n) => n.parse().map_err(|_e| malformed())?,
+                    None => *cols,
+                };
+                Layout::new(*rows, *cols, letters(parts[1])?, letters(parts[2])?, row_factor)
+            },
+            _ => Err(malformed()),
+        }
+    }
+}
+
+/// A seat, as given by a boarding pass such as `FBFBBFFRLR`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BoardingPass {
+    pub row: usize,
+    pub col: usize,
+    pub seat_id: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PassError {
+    BadLength { found: usize, expected: usize },
+    /// A letter that doesn't belong at the given 0-based position
+    BadLetter(usize, char),
+    /// A seat id with no seat on the plane
+    NoSuchSeat(usize),
+}
+
+impl fmt::Display for PassError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PassError::BadLength { found, expected } =>
+                write!(f, "pass has {} letters, expected {}", found, expected),
+            PassError::BadLetter(pos, letter) => write!(f, "unexpected letter {} at position {}", letter, pos),
+            PassError::NoSuchSeat(seat_id) => write!(f, "there is no seat {}", seat_id),
+        }
+    }
+}
+
+impl BoardingPass {
+    /// Decode a pass for the default `Layout`
+    pub fn decode(pass: &str) -> Result<Self, PassError> {
+        Layout::default().decode(pass)
+    }
+
+    /// Return the boarding pass for the seat with the given id in the default `Layout`
+    pub fn encode(seat_id: usize) -> Result<String, PassError> {
+        Layout::default().encode(seat_id)
+    }
+}
+
+/// Return the ids of the empty seats whose neighbors by id are both occupied,
+/// in increasing order.
+fn find_missing_seats(layout: &Layout, occupied: &HashSet<usize>) -> Vec<usize> {
+    let mut missing: Vec<usize> = occupied.iter()
+        .filter_map(|s| s.checked_add(1))
+        .filter(|s| !occupied.contains(s) && s.checked_add(1).is_some_and(|next| occupied.contains(&next)))
+        .filter(|s| layout.encode(*s).is_ok())
+        .collect();
+    missing.sort_unstable();
+    missing
+}
+
+/// Draw the plane one row per line, front first, with `#` for occupied seats,
+/// `.` for empty ones and `X` for `yours`, and an aisle down the middle.
+fn render_seat_map(layout: &Layout, occupied: &HashSet<usize>, yours: &[usize]) -> String {
+    let mut map = String::new();
+    for row in 0..layout.rows {
+        map.push_str(&format!("{:>4} ", row));
+        for col in 0..layout.cols {
+            if col == layout.cols / 2 && col > 0 {
+                map.push(' ');
+            }
+            let seat_id = row * layout.row_factor + col;
+            map.push(if yours.contains(&seat_id) {
+                'X'
+            } else if occupied.contains(&seat_id) {
+                '#'
+            } else {
+                '.'
+            });
+        }
+        map.push('\n');
+    }
+    map
+}
+
+/// Usage: d5 [--layout "ROWSxCOLS ROW_LETTERS COL_LETTERS [ROW_FACTOR]"] [--bsp] [--map]
+///
+/// `--bsp` decodes passes with the reference `Layout::decode_bsp`, and `--map`
+/// draws the seats taken.
 pub fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let layout = match args.iter().position(|arg| arg == "--layout").and_then(|i| args.get(i + 1)) {
+        Some(desc) => desc.parse::<Layout>().unwrap_or_else(|e| panic!("bad layout {}: {:?}", desc, e)),
+        None => Layout::default(),
+    };
+    let bsp = args.iter().any(|arg| arg == "--bsp");
     let mut max = 0;
     let mut seen_seats: HashSet<usize> = HashSet::new();
     for line in util::read_lines("inputs/d5").unwrap() {
         let line = line.unwrap();
-        let idx = line.find(|c| c == 'R' || c == 'L').unwrap();
-        let (row_cmd, seat_cmd) = line.split_at(idx);
-        let row = bsp_find(row_cmd, 0, 127);
-        let seat = bsp_find(seat_cmd, 0, 7);
-        let seat_id = row * 8 + seat;
-        // println!("{} -> row {} seat {} seat ID {}", line, row, seat, seat_id);
+        let pass = if bsp { layout.decode_bsp(line.trim()) } else { layout.decode(line.trim()) };
+        let pass = pass.unwrap_or_else(|e| panic!("bad pass {}: {}", line, e));
+        let seat_id = pass.seat_id;
         if seat_id > max {
             max = seat_id;
         }
         seen_seats.insert(seat_id);
     }
     println!("MAX {}", max);
-    for s in seen_seats.iter() {
-        if !seen_seats.contains(&(s-1)) || !seen_seats.contains(&(s+1)) {
-            println!("suspect seat {}", s);
-        }
+    let missing = find_missing_seats(&layout, &seen_seats);
+    for s in missing.iter() {
+        println!("missing seat {}", s);
+    }
+    if args.iter().any(|arg| arg == "--map") {
+        print!("{}", render_seat_map(&layout, &seen_seats, &missing));
     }
 }
 
-fn bsp_find(command: &str, lower: usize, upper: usize) -> usize {
-    let command_cs: Vec<char> = command.to_ascii_lowercase().chars().collect();
-    assert!((1 << command_cs.len()) == upper - lower + 1);
+/// Bisect the range `lower..=upper` by the letters of `command`, taking the lower
+/// half for `letters[0]` and the upper half for `letters[1]`.
+fn bsp_find(command: &str, lower: usize, upper: usize, letters: [char; 2]) -> Result<usize, PassError> {
+    let command_cs: Vec<char> = command.chars().collect();
+    let size = upper - lower + 1;
+    if 1usize.checked_shl(command_cs.len() as u32) != Some(size) {
+        let expected = size.trailing_zeros() as usize;
+        return Err(PassError::BadLength { found: command_cs.len(), expected });
+    }
     let mut lower = lower;
     let mut upper = upper;
-    for cmd in command_cs.iter() {
-        assert!((upper - lower  + 1) % 2 == 0);
-        let delta = (upper - lower + 1) / 2 - 1;
-        match cmd {
-            'f' | 'l' => {
-                upper = lower + delta;
-            },
-            'b' | 'r' => {
-                lower = upper - delta;
-            },
-            _ => panic!("unknown command {}", cmd),
+    for (pos, cmd) in command_cs.iter().enumerate() {
+        // the size of the range is a power of two
+        let delta = (upper - lower) / 2;
+        if *cmd == letters[0] {
+            upper = lower + delta;
+        } else if *cmd == letters[1] {
+            lower = upper - delta;
+        } else {
+            return Err(PassError::BadLetter(pos, *cmd));
         }
     }
-    if lower != upper {
-        panic!("search ended with ({}, {})", lower, upper)
-    }
-    lower
+    Ok(lower)
 }
 
 #[cfg(test)]
-mod test {
+mod test_d5 {
     use super::*;
+    use aoc_2020::rng::Rng;
+    use test::Bencher;
+
+    /// Random valid passes for `layout`, for equivalence tests and benchmarks
+    fn random_passes(layout: &Layout, n: usize, rng: &mut Rng) -> Vec<String> {
+        (0..n)
+            .map(|_| {
+                let seat_id = rng.gen_range(0, layout.rows) * layout.row_factor + rng.gen_range(0, layout.cols);
+                layout.encode(seat_id).unwrap()
+            })
+            .collect()
+    }
 
     #[test]
     fn test_bsp_find() {
-        assert_eq!(bsp_find("FBF", 0, 7), 2);
-        assert_eq!(bsp_find("FBFBBFF", 0, 127), 44);
+        assert_eq!(bsp_find("FBF", 0, 7, ['F', 'B']), Ok(2));
+        assert_eq!(bsp_find("FBFBBFF", 0, 127, ['F', 'B']), Ok(44));
+        assert_eq!(bsp_find("FBFB", 0, 7, ['F', 'B']), Err(PassError::BadLength { found: 4, expected: 3 }));
+        assert_eq!(bsp_find("FBL", 0, 7, ['F', 'B']), Err(PassError::BadLetter(2, 'L')));
+    }
+
+    #[test]
+    fn test_decode() {
+        let cases = [
+            ("FBFBBFFRLR", 44, 5, 357),
+            ("BFFFBBFRRR", 70, 7, 567),
+            ("FFFBBBFRRR", 14, 7, 119),
+            ("BBFFBBFRLL", 102, 4, 820),
+        ];
+        for (code, row, col, seat_id) in cases.iter() {
+            let pass = BoardingPass::decode(code).unwrap();
+            assert_eq!(pass, BoardingPass { row: *row, col: *col, seat_id: *seat_id });
+            assert_eq!(BoardingPass::encode(*seat_id).unwrap(), *code);
+        }
+        assert_eq!(BoardingPass::decode("FBFBBFFRL"), Err(PassError::BadLength { found: 9, expected: 10 }));
+        assert_eq!(BoardingPass::decode("FBFBBFRRLR"), Err(PassError::BadLetter(6, 'R')));
+        assert_eq!(BoardingPass::decode("fBFBBFFRLR"), Err(PassError::BadLetter(0, 'f')));
+        assert_eq!(BoardingPass::encode(1024), Err(PassError::NoSuchSeat(1024)));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for seat_id in 0..1024 {
+            let code = BoardingPass::encode(seat_id).unwrap();
+            assert_eq!(BoardingPass::decode(&code).unwrap().seat_id, seat_id);
+        }
+        let mut rng = Rng::new(5);
+        for _ in 0..1000 {
+            let code: String = (0..10)
+                .map(|pos| *rng.choose(if pos < 7 { &['F', 'B'] } else { &['L', 'R'] }).unwrap())
+                .collect();
+            let pass = BoardingPass::decode(&code).unwrap();
+            assert!(pass.row < 128 && pass.col < 8);
+            assert_eq!(BoardingPass::encode(pass.seat_id).unwrap(), code);
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        // a small plane with 4 rows of 2 seats, lettered like a compass
+        let layout: Layout = "4x2 NS WE 10".parse().unwrap();
+        let pass = layout.decode("SNE").unwrap();
+        assert_eq!(pass, BoardingPass { row: 2, col: 1, seat_id: 21 });
+        assert_eq!(layout.encode(21), Ok("SNE".to_string()));
+        assert_eq!(layout.encode(12), Err(PassError::NoSuchSeat(12)));
+        assert_eq!(layout.encode(40), Err(PassError::NoSuchSeat(40)));
+        assert_eq!(layout.decode("FBFBBFFRLR"), Err(PassError::BadLength { found: 10, expected: 3 }));
+        assert_eq!(layout.decode("SNS"), Err(PassError::BadLetter(2, 'S')));
+        for row in 0..4 {
+            for col in 0..2 {
+                let code = layout.encode(row * 10 + col).unwrap();
+                assert_eq!(layout.decode(&code), Ok(BoardingPass { row, col, seat_id: row * 10 + col }));
+            }
+        }
+
+        assert_eq!("128x8 FB LR".parse::<Layout>(), Ok(Layout::default()));
+        assert_eq!("100x8 FB LR".parse::<Layout>(), Err(LayoutError::NotPowerOfTwo(100)));
+        assert_eq!("128x8 FF LR".parse::<Layout>(), Err(LayoutError::SameLetters('F')));
+        assert_eq!("128x8 FB LR 4".parse::<Layout>(), Err(LayoutError::RowFactorTooSmall(4)));
+        assert!(matches!("128 FB LR".parse::<Layout>(), Err(LayoutError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_decode_matches_bsp() {
+        let mut rng = Rng::new(49);
+        for layout in [Layout::default(), "4x2 NS WE 10".parse().unwrap(), "1x1 AB CD".parse().unwrap()].iter() {
+            for pass in random_passes(layout, 500, &mut rng).iter() {
+                assert_eq!(layout.decode(pass), layout.decode_bsp(pass));
+            }
+        }
+        let layout = Layout::default();
+        let bad = ["", "FBFBBFFRL", "FBFBBFFRLRR", "FBFBBFFLLX", "FBFBBFFRL\u{e9}", "\u{e9}BFBBFFRL", "fBFBBFFRLR"];
+        for pass in bad.iter() {
+            assert!(layout.decode(pass).is_err());
+            assert_eq!(layout.decode(pass), layout.decode_bsp(pass), "{}", pass);
+        }
+        assert_eq!("128x8 F\u{e9} LR".parse::<Layout>(), Err(LayoutError::NotAscii('\u{e9}')));
+    }
+
+    #[test]
+    fn test_find_missing_seats() {
+        let layout = Layout::default();
+        // seat 0 is occupied, 1 and 2 are an empty pair, 7 is alone between 6 and 8
+        let occupied: HashSet<usize> = [0, 3, 4, 5, 6, 8, 9, 1023].iter().cloned().collect();
+        assert_eq!(find_missing_seats(&layout, &occupied), vec![7]);
+        let occupied: HashSet<usize> = (0..1024).filter(|s| *s != 500 && *s != 600).collect();
+        assert_eq!(find_missing_seats(&layout, &occupied), vec![500, 600]);
+        assert_eq!(find_missing_seats(&layout, &HashSet::new()), Vec::<usize>::new());
+        // ids 2..=9 aren't seats when rows are 10 ids apart
+        let layout: Layout = "4x2 NS WE 10".parse().unwrap();
+        let occupied: HashSet<usize> = [1, 3, 10, 12].iter().cloned().collect();
+        assert_eq!(find_missing_seats(&layout, &occupied), vec![11]);
+    }
+
+    #[test]
+    fn test_render_seat_map() {
+        let layout: Layout = "4x4 FB LR".parse().unwrap();
+        let occupied: HashSet<usize> = (0..16).filter(|s| *s != 6 && *s != 15).collect();
+        assert_eq!(find_missing_seats(&layout, &occupied), vec![6]);
+        assert_eq!(render_seat_map(&layout, &occupied, &[6]), "   0 ## ##\n   1 ## X#\n   2 ## ##\n   3 ## #.\n");
+    }
+
+    #[bench]
+    fn bench_decode_bits(b: &mut Bencher) {
+        let layout = Layout::default();
+        let passes = random_passes(&layout, 10_000, &mut Rng::new(1));
+        b.iter(|| passes.iter().map(|pass| layout.decode(pass).unwrap().seat_id).max());
+    }
+
+    #[bench]
+    fn bench_decode_bsp(b: &mut Bencher) {
+        let layout = Layout::default();
+        let passes = random_passes(&layout, 10_000, &mut Rng::new(1));
+        b.iter(|| passes.iter().map(|pass| layout.decode_bsp(pass).unwrap().seat_id).max());
     }
 }
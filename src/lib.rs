@@ -99,3 +99,275 @@ pub mod cylinder {
         }
     }
 }
+
+pub mod rng {
+    /// Small deterministic pseudo-random number generator (SplitMix64), so that
+    /// generated test data can be reproduced from its seed.
+    pub struct Rng(u64);
+
+    impl Rng {
+        pub fn new(seed: u64) -> Self {
+            Rng(seed)
+        }
+
+        pub fn next_u64(&mut self) -> u64 {
+            self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = self.0;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        }
+
+        /// Return a uniformly distributed integer in `lo..hi`. Panics if the range is empty.
+        pub fn gen_range(&mut self, lo: usize, hi: usize) -> usize {
+            assert!(lo < hi, "empty range {}..{}", lo, hi);
+            lo + (self.next_u64() % (hi - lo) as u64) as usize
+        }
+
+        /// Return true with probability `p`
+        pub fn gen_bool(&mut self, p: f64) -> bool {
+            ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+        }
+
+        /// Return a uniformly chosen element of `items`, or None if it is empty
+        pub fn choose<'a, T>(&mut self, items: &'a [T]) -> Option<&'a T> {
+            if items.is_empty() {
+                None
+            } else {
+                Some(&items[self.gen_range(0, items.len())])
+            }
+        }
+
+        /// Shuffle `items` in place (Fisher-Yates)
+        pub fn shuffle<T>(&mut self, items: &mut [T]) {
+            for i in (1..items.len()).rev() {
+                items.swap(i, self.gen_range(0, i + 1));
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod test_rng {
+        use super::*;
+
+        #[test]
+        fn test_rng_deterministic() {
+            let mut a = Rng::new(2020);
+            let mut b = Rng::new(2020);
+            for _ in 0..100 {
+                assert_eq!(a.next_u64(), b.next_u64());
+            }
+            assert_ne!(Rng::new(1).next_u64(), Rng::new(2).next_u64());
+        }
+
+        #[test]
+        fn test_rng_ranges() {
+            let mut rng = Rng::new(7);
+            let mut seen = [false; 5];
+            for _ in 0..1000 {
+                let x = rng.gen_range(3, 8);
+                assert!((3..8).contains(&x));
+                seen[x - 3] = true;
+            }
+            assert!(seen.iter().all(|s| *s));
+            assert_eq!(rng.choose::<u8>(&[]), None);
+
+            let mut items: Vec<usize> = (0..20).collect();
+            rng.shuffle(&mut items);
+            items.sort();
+            assert_eq!(items, (0..20).collect::<Vec<usize>>());
+        }
+    }
+}
+
+pub mod json {
+    use std::iter::Peekable;
+    use std::str::Chars;
+
+    /// Quote and escape `s` as a JSON string
+    pub fn quote(s: &str) -> String {
+        let mut out = String::from("\"");
+        for c in s.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+        out
+    }
+
+    /// Parse a JSON object whose values are strings, numbers or null into its
+    /// keys and values, in order. Numbers are returned as written and null
+    /// values are dropped.
+    pub fn parse_flat_object(text: &str) -> Result<Vec<(String, String)>, String> {
+        let mut chars = text.chars().peekable();
+        let mut fields = Vec::new();
+        skip_whitespace(&mut chars);
+        expect(&mut chars, '{')?;
+        skip_whitespace(&mut chars);
+        if chars.peek() == Some(&'}') {
+            chars.next();
+        } else {
+            loop {
+                skip_whitespace(&mut chars);
+                let key = parse_string(&mut chars)?;
+                skip_whitespace(&mut chars);
+                expect(&mut chars, ':')?;
+                skip_whitespace(&mut chars);
+                match chars.peek() {
+                    Some('"') => fields.push((key, parse_string(&mut chars)?)),
+                    Some(c) if *c == '-' || c.is_ascii_digit() => {
+                        let mut num = String::new();
+                        while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || "+-.eE".contains(*c)) {
+                            num.push(c);
+                        }
+                        if !is_json_number(&num) {
+                            return Err(format!("invalid number {}", num));
+                        }
+                        fields.push((key, num));
+                    },
+                    Some('n') => "null".chars().try_for_each(|c| expect(&mut chars, c))?,
+                    _ => return Err(format!("value of {} is not a string, number or null", key)),
+                }
+                skip_whitespace(&mut chars);
+                match chars.next() {
+                    Some(',') => continue,
+                    Some('}') => break,
+                    _ => return Err("expected , or }".to_string()),
+                }
+            }
+        }
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            None => Ok(fields),
+            Some(c) => Err(format!("unexpected '{}' after object", c)),
+        }
+    }
+
+    fn skip_whitespace(chars: &mut Peekable<Chars>) {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn expect(chars: &mut Peekable<Chars>, expected: char) -> Result<(), String> {
+        match chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("expected '{}', found '{}'", expected, c)),
+            None => Err(format!("expected '{}', found end of input", expected)),
+        }
+    }
+
+    fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+        expect(chars, '"')?;
+        let mut out = String::new();
+        loop {
+            match chars.next().ok_or("unterminated string")? {
+                '"' => return Ok(out),
+                '\\' => match chars.next().ok_or("unterminated string")? {
+                    'b' => out.push('\u{8}'),
+                    'f' => out.push('\u{c}'),
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    'u' => {
+                        let mut code = parse_hex4(chars)?;
+                        // a surrogate pair encodes a code point outside the basic plane
+                        if (0xd800..0xdc00).contains(&code) {
+                            expect(chars, '\\')?;
+                            expect(chars, 'u')?;
+                            let low = parse_hex4(chars)?;
+                            if !(0xdc00..0xe000).contains(&low) {
+                                return Err("invalid surrogate pair".to_string());
+                            }
+                            code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                        }
+                        out.push(std::char::from_u32(code).ok_or("invalid \\u escape")?);
+                    },
+                    c @ ('"' | '\\' | '/') => out.push(c),
+                    c => return Err(format!("invalid escape \\{}", c)),
+                },
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn parse_hex4(chars: &mut Peekable<Chars>) -> Result<u32, String> {
+        let hex: String = chars.take(4).collect();
+        if hex.chars().count() != 4 {
+            return Err("unterminated \\u escape".to_string());
+        }
+        // from_str_radix would also accept a sign
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("invalid \\u escape {}", hex));
+        }
+        u32::from_str_radix(&hex, 16).map_err(|_e| format!("invalid \\u escape {}", hex))
+    }
+
+    /// Return true iff. `num` follows the JSON number grammar,
+    /// `-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?`
+    fn is_json_number(num: &str) -> bool {
+        let digits = |s: &str| s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let rest = num.strip_prefix('-').unwrap_or(num);
+        let int = digits(rest);
+        if int == 0 || (int > 1 && rest.starts_with('0')) {
+            return false;
+        }
+        let mut rest = &rest[int..];
+        if let Some(frac) = rest.strip_prefix('.') {
+            let n = digits(frac);
+            if n == 0 {
+                return false;
+            }
+            rest = &frac[n..];
+        }
+        if let Some(exp) = rest.strip_prefix(['e', 'E']) {
+            let exp = exp.strip_prefix(['+', '-']).unwrap_or(exp);
+            let n = digits(exp);
+            if n == 0 {
+                return false;
+            }
+            rest = &exp[n..];
+        }
+        rest.is_empty()
+    }
+
+    #[cfg(test)]
+    mod test_json {
+        use super::*;
+
+        #[test]
+        fn test_quote() {
+            assert_eq!(quote("say \"hi\"\n"), "\"say \\\"hi\\\"\\u000a\"");
+            assert_eq!(quote("a\\b"), "\"a\\\\b\"");
+        }
+
+        #[test]
+        fn test_parse_flat_object() {
+            let fields = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+                pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+            };
+            assert_eq!(parse_flat_object(" {} "), Ok(vec![]));
+            assert_eq!(parse_flat_object(r#"{"byr": "1937", "cid" : 147, "x": null}"#),
+                       Ok(fields(&[("byr", "1937"), ("cid", "147")])));
+            assert_eq!(parse_flat_object(r#"{"a\"b": "\u00e9\n\ud83d\ude00\/"}"#),
+                       Ok(fields(&[("a\"b", "\u{e9}\n\u{1f600}/")])));
+            let original = "tab\t \"quoted\" \\ \u{1}";
+            assert_eq!(parse_flat_object(&format!("{{{}: {}}}", quote("k"), quote(original))),
+                       Ok(fields(&[("k", original)])));
+            assert!(parse_flat_object(r#"{"a": true}"#).is_err());
+            assert!(parse_flat_object(r#"{"a": "b""#).is_err());
+            assert!(parse_flat_object(r#"{"a": "b"} x"#).is_err());
+            assert!(parse_flat_object(r#"{"a": "\q"}"#).is_err());
+            assert_eq!(parse_flat_object(r#"{"a": "\ud800\udbff"}"#), Err("invalid surrogate pair".to_string()));
+            assert!(parse_flat_object(r#"{"a": "\u+041"}"#).is_err());
+            assert!(parse_flat_object("{\"a\": \"\\u00\u{e9}9\"}").unwrap_err().starts_with("invalid"));
+            assert_eq!(parse_flat_object(r#"{"a": -0.5e+3, "b": 10E2}"#),
+                       Ok(fields(&[("a", "-0.5e+3"), ("b", "10E2")])));
+            for bad in ["1-2e+", "01", "-", "1.", "1e", "2.e3"].iter() {
+                assert!(parse_flat_object(&format!("{{\"b\": {}}}", bad)).is_err(), "{}", bad);
+            }
+        }
+    }
+}